@@ -2,7 +2,7 @@ use std::{cell::RefCell, rc::Rc};
 
 use wasm_bindgen::{closure::Closure, JsCast, JsValue};
 use web_sys::KeyboardEvent;
-use wire_universe::CellState;
+use wire_universe::{proto::FromClient, CellState};
 
 use crate::{state::State, util::window};
 
@@ -22,6 +22,26 @@ fn process_key(st: Rc<RefCell<State>>, event: KeyboardEvent) {
         "s" => st.set_brush(CellState::Alive).unwrap(),
         "d" => st.set_brush(CellState::Dead).unwrap(),
         "f" => st.set_brush(CellState::Empty).unwrap(),
+        "x" => st.fork(false).unwrap(),
+        "X" => st.fork(true).unwrap(),
         _ => {}
     }
+    let Some(sandbox) = st.sandbox else {
+        return;
+    };
+    let msg = match event.key().as_ref() {
+        "p" if sandbox.paused => FromClient::Resume,
+        "p" => FromClient::Pause,
+        "n" => FromClient::Step { generations: 1 },
+        "[" => FromClient::SetSpeed {
+            tick_ms: sandbox.tick_ms * 2,
+        },
+        "]" => FromClient::SetSpeed {
+            tick_ms: sandbox.tick_ms / 2,
+        },
+        "m" => FromClient::Merge,
+        "Escape" => FromClient::LeaveSandbox,
+        _ => return,
+    };
+    st.send(&msg).unwrap();
 }
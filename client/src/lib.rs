@@ -1,19 +1,18 @@
-use std::{any::Any, cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use js_sys::Object;
 use state::{Command, MousedownState, State};
 use util::document;
 use wasm_bindgen::prelude::*;
 
 use web_sys::{MessageEvent, WebSocket, WheelEvent};
 use wire_universe::{
-    proto::{FromClient, FromServer},
+    proto::{FromClient, FromServer, PROTOCOL_VERSION},
     CellState,
 };
 
 use crate::{
     keyboard::install_keyhandler,
-    state::{Viewport, World},
+    state::{Sandbox, Viewport, World},
     util::console_log,
 };
 
@@ -21,6 +20,17 @@ mod keyboard;
 mod state;
 mod util;
 
+/// The optional parts of the protocol the client supports
+const CAPABILITIES: &[&str] = &[
+    "sandbox",
+    "pan",
+    "catch-up",
+    "replay",
+    "checksum",
+    "packed",
+    "run-length",
+];
+
 fn init_wheel_zoomer(st: Rc<RefCell<State>>) {
     let callback = Closure::<dyn FnMut(_)>::new({
         let st = st.clone();
@@ -53,19 +63,141 @@ fn init_websocket(st: Rc<RefCell<State>>) {
                         let data = js_sys::Uint8Array::new(&fr_c.result().unwrap()).to_vec();
                         if let Ok(val) = rmp_serde::from_slice::<FromServer>(&data) {
                             match val {
-                                FromServer::FullRefresh { x, y, tiles } => {
+                                FromServer::Welcome {
+                                    tick_ms,
+                                    generation,
+                                    world,
+                                    ..
+                                } => {
+                                    console_log!(
+                                        "Connected to `{}' at generation {}, {}ms per generation",
+                                        world,
+                                        generation,
+                                        tick_ms
+                                    );
+                                }
+                                FromServer::Rejected { reason } => {
+                                    console_log!("The server turned us away: {}", reason);
+                                }
+                                FromServer::FullRefresh {
+                                    generation,
+                                    x,
+                                    y,
+                                    tiles,
+                                } => {
+                                    let Some(tiles) = tiles.decode() else {
+                                        console_log!("Couldn't decode a refresh");
+                                        return;
+                                    };
                                     let st = &mut st.borrow_mut();
                                     let world = &mut st.world;
                                     world.tiles = tiles;
                                     world.x = x;
                                     world.y = y;
+                                    st.pending.clear();
+                                    st.generation = Some(generation);
+                                    st.render_tiles().unwrap();
+                                }
+                                msg @ (FromServer::Chunk { .. } | FromServer::ChunkRing { .. }) => {
+                                    st.borrow_mut().pending.push(msg);
+                                }
+                                FromServer::Tick { generation } => {
+                                    let st = &mut st.borrow_mut();
+                                    st.advance(generation.checked_sub(1), generation).unwrap();
+                                    st.world.step();
+                                    for msg in std::mem::take(&mut st.pending) {
+                                        match msg {
+                                            FromServer::Chunk { x, y, tiles, .. } => {
+                                                st.world.set_chunk(x, y, &tiles)
+                                            }
+                                            FromServer::ChunkRing { x, y, tiles, .. } => {
+                                                st.world.set_ring(x, y, &tiles)
+                                            }
+                                            _ => {}
+                                        }
+                                    }
+                                    st.render_tiles().unwrap();
+                                }
+                                FromServer::Ticks {
+                                    generation,
+                                    count,
+                                    rings,
+                                } => {
+                                    let st = &mut st.borrow_mut();
+                                    st.advance(generation.checked_sub(count as u64), generation)
+                                        .unwrap();
+                                    st.world.replay(count, &rings);
                                     st.render_tiles().unwrap();
                                 }
-                                FromServer::PartialRefresh { tiles } => {
+                                FromServer::CatchUp {
+                                    generation,
+                                    x,
+                                    y,
+                                    cells,
+                                } => {
                                     let st = &mut st.borrow_mut();
-                                    st.world.step(tiles);
+                                    for (dx, dy, cell) in cells {
+                                        st.world.set_cell(x + dx as i32, y + dy as i32, cell);
+                                    }
+                                    st.pending.clear();
+                                    st.generation = Some(generation);
                                     st.render_tiles().unwrap();
                                 }
+                                FromServer::Pan {
+                                    generation,
+                                    x,
+                                    y,
+                                    w,
+                                    h,
+                                    chunks,
+                                } => {
+                                    let st = &mut st.borrow_mut();
+                                    st.advance(Some(generation), generation).unwrap();
+                                    st.world.pan(x, y, w, h);
+                                    for (x, y, tiles) in chunks {
+                                        match tiles.decode() {
+                                            Some(tiles) => {
+                                                st.world.set_chunk(x, y, &tiles.concat())
+                                            }
+                                            None => console_log!("Couldn't decode a chunk"),
+                                        }
+                                    }
+                                    st.render_tiles().unwrap();
+                                }
+                                FromServer::Checksum {
+                                    generation,
+                                    checksum,
+                                } => {
+                                    st.borrow().check(generation, checksum).unwrap();
+                                }
+                                FromServer::Ack { request_id } => {
+                                    st.borrow_mut().edits.remove(&request_id);
+                                }
+                                FromServer::Error {
+                                    code,
+                                    message,
+                                    request_id,
+                                } => {
+                                    st.borrow_mut().refused(code, &message, request_id).unwrap();
+                                }
+                                FromServer::SandboxStatus {
+                                    paused,
+                                    tick_ms,
+                                    generation,
+                                } => {
+                                    st.borrow_mut()
+                                        .set_sandbox(Some(Sandbox {
+                                            paused,
+                                            tick_ms,
+                                            generation,
+                                        }))
+                                        .unwrap();
+                                }
+                                FromServer::SandboxClosed => {
+                                    st.borrow_mut().set_sandbox(None).unwrap();
+                                }
+                                // only the main view is ever opened
+                                FromServer::InView { .. } => {}
                             }
                         }
                     });
@@ -78,12 +210,15 @@ fn init_websocket(st: Rc<RefCell<State>>) {
     ws.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
     onmessage_callback.forget();
 
-    let cloned_ws = ws.clone();
     let onopen_callback = Closure::<dyn FnMut()>::new(move || {
-        st.borrow().send_viewport().unwrap();
-        cloned_ws
-            .send_with_u8_array(&rmp_serde::to_vec(&FromClient::StartStream).unwrap())
-            .unwrap();
+        let st = st.borrow();
+        st.send(&FromClient::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        })
+        .unwrap();
+        st.send_viewport().unwrap();
+        st.send(&FromClient::StartStream).unwrap();
     });
     ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
     onopen_callback.forget();
@@ -239,6 +374,11 @@ fn start() -> Result<(), JsValue> {
         zoom_float: 20.,
         socket,
         mousedown_state: None,
+        sandbox: None,
+        pending: Vec::new(),
+        generation: None,
+        next_request: 0,
+        edits: HashMap::new(),
     };
     st.sync_canvas_size();
     let st = Rc::new(RefCell::new(st));
@@ -247,5 +387,5 @@ fn start() -> Result<(), JsValue> {
     init_wheel_zoomer(st.clone());
     install_keyhandler(st.clone())?;
     init_input_callbacks(st);
-    return Ok(());
+    Ok(())
 }
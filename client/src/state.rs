@@ -1,6 +1,11 @@
+use std::collections::HashMap;
+
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, HtmlElement, WebSocket};
-use wire_universe::{proto::FromClient, CellState, Point};
+use wire_universe::{
+    proto::{self, ErrorCode, FromClient, FromServer, Rect, CHUNK_SIZE},
+    CellState,
+};
 
 use crate::util::{console_log, document};
 
@@ -14,6 +19,20 @@ pub struct Viewport {
 
 const TILE_BUFFER: f64 = 0.3;
 
+// whether a row or column is on the edge of a chunk
+fn on_ring(c: i32) -> bool {
+    let c = c.rem_euclid(CHUNK_SIZE);
+    c == 0 || c == CHUNK_SIZE - 1
+}
+
+/// The controls of the sandbox being looked at, as last told by the server
+#[derive(Debug, Clone, Copy)]
+pub struct Sandbox {
+    pub paused: bool,
+    pub tick_ms: u64,
+    pub generation: u64,
+}
+
 #[derive(Debug, Clone)]
 pub struct World {
     pub x: i32,
@@ -22,59 +41,30 @@ pub struct World {
 }
 
 impl World {
-    // step the automaton, provided with with the new outside cells
-    pub fn step(&mut self, data: Vec<CellState>) {
+    // step the inside of every chunk; the outermost ring of each comes from the server
+    pub fn step(&mut self) {
         let ot = self.tiles.clone();
-        for y in 1..self.tiles.len() - 1 {
-            for x in 1..self.tiles[0].len() - 1 {
-                let pos = Point {
-                    x: x as i32,
-                    y: y as i32,
-                };
-                let next = match ot[y][x] {
+        let h = ot.len();
+        let w = ot.first().map_or(0, |row| row.len());
+        for y in 1..h.saturating_sub(1) {
+            if on_ring(self.y + y as i32) {
+                continue;
+            }
+            for x in 1..w.saturating_sub(1) {
+                if on_ring(self.x + x as i32) {
+                    continue;
+                }
+                self.tiles[y][x] = match ot[y][x] {
                     CellState::Alive => CellState::Dead,
                     CellState::Dead => CellState::Wire,
                     CellState::Empty => CellState::Empty,
                     CellState::Wire => {
-                        let mut n = 0;
-                        for i in [
-                            Point {
-                                x: pos.x - 1,
-                                y: pos.y - 1,
-                            },
-                            Point {
-                                x: pos.x,
-                                y: pos.y - 1,
-                            },
-                            Point {
-                                x: pos.x + 1,
-                                y: pos.y - 1,
-                            },
-                            Point {
-                                x: pos.x - 1,
-                                y: pos.y,
-                            },
-                            Point {
-                                x: pos.x + 1,
-                                y: pos.y,
-                            },
-                            Point {
-                                x: pos.x - 1,
-                                y: pos.y + 1,
-                            },
-                            Point {
-                                x: pos.x,
-                                y: pos.y + 1,
-                            },
-                            Point {
-                                x: pos.x + 1,
-                                y: pos.y + 1,
-                            },
-                        ] {
-                            if ot[i.y as usize][i.x as usize] == CellState::Alive {
-                                n += 1;
-                            }
-                        }
+                        // the cell itself is wire, so it isn't counted
+                        let n = ot[y - 1..=y + 1]
+                            .iter()
+                            .flat_map(|row| &row[x - 1..=x + 1])
+                            .filter(|&&c| c == CellState::Alive)
+                            .count();
                         if n == 1 || n == 2 {
                             CellState::Alive
                         } else {
@@ -82,34 +72,59 @@ impl World {
                         }
                     }
                 };
-                self.tiles[y][x] = next;
-            }
-            assert_eq!(
-                data.len(),
-                self.tiles.len() * 2 + self.tiles[0].len() * 2 - 4,
-                "wrongly sized data: data != expected"
-            );
-            let mut i = 0;
-            let w = self.tiles[0].len();
-            let h = self.tiles.len();
-            for y in 0..h {
-                self.tiles[y][0] = data[i];
-                i += 1;
             }
-            for x in 1..w {
-                self.tiles[h - 1][x] = data[i];
-                i += 1;
-            }
-            for y in (1..h).rev() {
-                self.tiles[y][w - 1] = data[i];
-                i += 1;
+        }
+    }
+    // replace a chunk's cells, given row by row
+    pub fn set_chunk(&mut self, x: i32, y: i32, tiles: &[CellState]) {
+        for (i, &cell) in tiles.iter().enumerate() {
+            let i = i as i32;
+            self.set_cell(x + i % CHUNK_SIZE, y + i / CHUNK_SIZE, cell);
+        }
+    }
+    // step `count` generations, setting the rings that changed after each
+    pub fn replay(&mut self, count: u32, rings: &[(i32, i32, Vec<Vec<CellState>>)]) {
+        for i in 0..count as usize {
+            self.step();
+            for (x, y, chunk_rings) in rings {
+                if let Some(ring) = chunk_rings.get(i) {
+                    self.set_ring(*x, *y, ring);
+                }
             }
-            for x in (1..w - 1).rev() {
-                self.tiles[0][x] = data[i];
-                i += 1;
+        }
+    }
+    // replace a chunk's outermost ring, given counter-clockwise from the top left
+    pub fn set_ring(&mut self, x: i32, y: i32, tiles: &[CellState]) {
+        let last = CHUNK_SIZE - 1;
+        let ring = (0..CHUNK_SIZE)
+            .map(|dy| (0, dy))
+            .chain((1..CHUNK_SIZE).map(|dx| (dx, last)))
+            .chain((1..last).rev().map(|dy| (last, dy)))
+            .chain((1..CHUNK_SIZE).rev().map(|dx| (dx, 0)));
+        for ((dx, dy), &cell) in ring.zip(tiles) {
+            self.set_cell(x + dx, y + dy, cell);
+        }
+    }
+    // move to a view of `w` by `h` tiles from (`x`, `y`), keeping the tiles in both
+    pub fn pan(&mut self, x: i32, y: i32, w: i32, h: i32) {
+        let old = std::mem::replace(
+            self,
+            World {
+                x,
+                y,
+                tiles: vec![vec![CellState::Empty; w as usize]; h as usize],
+            },
+        );
+        for (dy, row) in old.tiles.into_iter().enumerate() {
+            for (dx, cell) in row.into_iter().enumerate() {
+                self.set_cell(old.x + dx as i32, old.y + dy as i32, cell);
             }
         }
     }
+    // whether the tiles are what the server's `checksum` was worked out from
+    pub fn matches(&self, checksum: u32) -> bool {
+        proto::checksum(&self.tiles) == checksum
+    }
     pub fn get_cell(&self, x: i32, y: i32) -> Option<CellState> {
         let iy = y - self.y;
         let ix = x - self.x;
@@ -149,6 +164,16 @@ pub struct State {
     pub zoom_float: f64,
     pub socket: WebSocket,
     pub mousedown_state: Option<MousedownState>,
+    /// `None` while looking at the shared world
+    pub sandbox: Option<Sandbox>,
+    /// `Chunk`s and `ChunkRing`s to apply on the next `Tick`
+    pub pending: Vec<FromServer>,
+    /// the generation shown, once the view's been sent
+    pub generation: Option<u64>,
+    /// the id for the next `Request`
+    pub next_request: u32,
+    /// where the edits sent as requests that haven't been answered yet were
+    pub edits: HashMap<u32, (i32, i32)>,
 }
 
 #[derive(Clone, Debug)]
@@ -267,7 +292,7 @@ impl State {
             CellState::Empty => "white",
             CellState::Wire => "orange",
         };
-        ctx.set_fill_style(&JsValue::from_str(color));
+        ctx.set_fill_style_str(color);
         ctx.fill_rect(
             (x * self.zoom - self.viewport.x) as f64,
             (y * self.zoom - self.viewport.y) as f64,
@@ -288,7 +313,7 @@ impl State {
             .get_context("2d")?
             .unwrap()
             .dyn_into::<CanvasRenderingContext2d>()?;
-        ctx.set_stroke_style(&JsValue::from_str("black"));
+        ctx.set_stroke_style_str("black");
         ctx.stroke_rect(
             (x * self.zoom - self.viewport.x) as f64,
             (y * self.zoom - self.viewport.y) as f64,
@@ -331,16 +356,100 @@ impl State {
             h: (self.viewport.h / self.zoom) + 1 + buffer_y * 2,
         }
     }
+    pub fn send(&self, msg: &FromClient) -> Result<(), JsValue> {
+        self.socket
+            .send_with_u8_array(&rmp_serde::to_vec(msg).unwrap())
+    }
+    /// Send `msg` as a `Request`, giving its id
+    pub fn request(&mut self, msg: FromClient) -> Result<u32, JsValue> {
+        let id = self.next_request;
+        self.next_request = self.next_request.wrapping_add(1);
+        self.send(&FromClient::Request {
+            id,
+            request: Box::new(msg),
+        })?;
+        Ok(id)
+    }
+    /// Deal with the server refusing a message, undoing it if it was an edit
+    pub fn refused(
+        &mut self,
+        code: ErrorCode,
+        message: &str,
+        request_id: Option<u32>,
+    ) -> Result<(), JsValue> {
+        match request_id.and_then(|id| self.edits.remove(&id)) {
+            Some((x, y)) => {
+                console_log!("Edit at ({}, {}) refused, {:?}: {}", x, y, code, message);
+                // the edit was already shown
+                self.send(&FromClient::Resync)?;
+            }
+            None => console_log!("Refused, {:?}: {}", code, message),
+        }
+        Ok(())
+    }
+    /// Move on to `generation` from `from`, asking to resync if that isn't the generation
+    /// shown, or if there's no `from` because the message counted back past generation 0
+    pub fn advance(&mut self, from: Option<u64>, generation: u64) -> Result<(), JsValue> {
+        let Some(from) = from else {
+            console_log!(
+                "Can't get to generation {} from before generation 0",
+                generation
+            );
+            return self.send(&FromClient::Resync);
+        };
+        if self.generation.is_some_and(|g| g != from) {
+            console_log!("Expected generation {:?}, got {}", self.generation, from);
+            self.send(&FromClient::Resync)?;
+        }
+        self.generation = Some(generation);
+        Ok(())
+    }
+    /// Check the view against the server's checksum of it, asking to resync if it's wrong
+    pub fn check(&self, generation: u64, checksum: u32) -> Result<(), JsValue> {
+        if self.generation == Some(generation) && !self.world.matches(checksum) {
+            console_log!("View doesn't match at generation {}", generation);
+            self.send(&FromClient::Resync)?;
+        }
+        Ok(())
+    }
     pub fn send_viewport(&self) -> Result<(), JsValue> {
         let tvp = self.tile_viewport();
-        let msg = FromClient::SetView {
+        self.send(&FromClient::SetView {
+            x: tvp.x,
+            y: tvp.y,
+            w: tvp.w,
+            h: tvp.h,
+        })
+    }
+    /// Fork the tiles loaded around the viewport into a sandbox, or the whole world
+    pub fn fork(&self, whole: bool) -> Result<(), JsValue> {
+        let tvp = self.tile_viewport();
+        let region = (!whole).then_some(Rect {
             x: tvp.x,
             y: tvp.y,
             w: tvp.w,
             h: tvp.h,
+        });
+        self.send(&FromClient::Fork { region })
+    }
+    pub fn set_sandbox(&mut self, sandbox: Option<Sandbox>) -> Result<(), JsValue> {
+        self.sandbox = sandbox;
+        let text = match sandbox {
+            None => String::new(),
+            Some(sb) => format!(
+                "sandbox, generation {}, {}",
+                sb.generation,
+                if sb.paused {
+                    "paused".to_owned()
+                } else {
+                    format!("{} ms per generation", sb.tick_ms)
+                }
+            ),
         };
-        self.socket
-            .send_with_u8_array(&rmp_serde::to_vec(&msg).unwrap())?;
+        document()?
+            .get_element_by_id("sandbox-status")
+            .ok_or(JsValue::from_str("#sandbox-status missing"))?
+            .set_text_content(Some(&text));
         Ok(())
     }
     fn set_zoom(&mut self, zoom: i32) -> Result<(), JsValue> {
@@ -373,13 +482,12 @@ impl State {
             Command::TileClick { x, y } => {
                 self.world.set_cell(x, y, self.brush);
                 self.paint_tile(&self.canvas, self.brush, x, y)?;
-                let msg = FromClient::ModifyCell {
+                let id = self.request(FromClient::ModifyCell {
                     x,
                     y,
                     cell: self.brush,
-                };
-                self.socket
-                    .send_with_u8_array(&rmp_serde::to_vec(&msg).unwrap())?;
+                })?;
+                self.edits.insert(id, (x, y));
             }
             Command::MouseDrag {
                 start_x,
@@ -393,13 +501,7 @@ impl State {
                 self.send_viewport()?;
             }
             Command::Zoom { amount } => {
-                self.zoom_float *= 1.05_f64.powf(amount);
-                if self.zoom_float < 5. {
-                    self.zoom_float = 5.;
-                }
-                if self.zoom_float > 80. {
-                    self.zoom_float = 80.;
-                }
+                self.zoom_float = (self.zoom_float * 1.05_f64.powf(amount)).clamp(5., 80.);
                 let new_zoom = self.zoom_float as i32;
                 if new_zoom != self.zoom {
                     self.set_zoom(new_zoom)?;
@@ -415,3 +517,157 @@ impl State {
         self.brush_canvas.set_height(self.viewport.h as u32);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two chunks side by side, with an electron on a wire about to cross from one into the
+    // other
+    fn two_chunks() -> World {
+        let mut world = World {
+            x: 0,
+            y: 0,
+            tiles: vec![vec![CellState::Empty; 2 * CHUNK_SIZE as usize]; CHUNK_SIZE as usize],
+        };
+        for x in 20..45 {
+            world.set_cell(x, 5, CellState::Wire);
+        }
+        world.set_cell(20, 5, CellState::Dead);
+        world.set_cell(21, 5, CellState::Alive);
+        world
+    }
+
+    // every cell stepped, as the server does
+    fn step_all(tiles: &[Vec<CellState>]) -> Vec<Vec<CellState>> {
+        let at = |x: i32, y: i32| {
+            let row = tiles.get(usize::try_from(y).ok()?)?;
+            row.get(usize::try_from(x).ok()?).copied()
+        };
+        let mut next = tiles.to_vec();
+        for (y, row) in next.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                let (x, y) = (x as i32, y as i32);
+                *cell = match *cell {
+                    CellState::Alive => CellState::Dead,
+                    CellState::Dead => CellState::Wire,
+                    CellState::Empty => CellState::Empty,
+                    CellState::Wire => {
+                        let n = (y - 1..=y + 1)
+                            .flat_map(|y| (x - 1..=x + 1).map(move |x| (x, y)))
+                            .filter(|&(x, y)| at(x, y) == Some(CellState::Alive))
+                            .count();
+                        if n == 1 || n == 2 {
+                            CellState::Alive
+                        } else {
+                            CellState::Wire
+                        }
+                    }
+                };
+            }
+        }
+        next
+    }
+
+    // the ring of the chunk at (`x`, `y`) of `world`: down the left, along the bottom, up
+    // the right and back along the top
+    fn ring(world: &World, x: i32, y: i32) -> Vec<CellState> {
+        let last = CHUNK_SIZE - 1;
+        let mut ring = Vec::new();
+        for dy in 0..=last {
+            ring.push((0, dy));
+        }
+        for dx in 1..=last {
+            ring.push((dx, last));
+        }
+        for dy in (1..last).rev() {
+            ring.push((last, dy));
+        }
+        for dx in (1..=last).rev() {
+            ring.push((dx, 0));
+        }
+        ring.into_iter()
+            .map(|(dx, dy)| world.get_cell(x + dx, y + dy).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ring_order() {
+        let last = CHUNK_SIZE - 1;
+        for (i, pos) in [
+            (0, (0, 0)),
+            (last, (0, last)),
+            (last + 1, (1, last)),
+            (2 * last, (last, last)),
+            (2 * last + 1, (last, last - 1)),
+            (3 * last - 1, (last, 1)),
+            (3 * last, (last, 0)),
+            (4 * last - 1, (1, 0)),
+        ] {
+            let mut world = two_chunks();
+            let mut tiles = ring(&world, CHUNK_SIZE, 0);
+            tiles[i as usize] = CellState::Alive;
+            world.set_ring(CHUNK_SIZE, 0, &tiles);
+            assert_eq!(
+                world.get_cell(CHUNK_SIZE + pos.0, pos.1),
+                Some(CellState::Alive),
+                "{}",
+                i
+            );
+            assert_eq!(ring(&world, CHUNK_SIZE, 0), tiles);
+        }
+    }
+
+    #[test]
+    fn replaying_rings() {
+        let mut expected = two_chunks();
+        let mut rings = vec![(0, 0, Vec::new()), (CHUNK_SIZE, 0, Vec::new())];
+        for _ in 0..20 {
+            expected.tiles = step_all(&expected.tiles);
+            for (x, y, chunk_rings) in &mut rings {
+                chunk_rings.push(ring(&expected, *x, *y));
+            }
+        }
+        let mut world = two_chunks();
+        world.replay(20, &rings);
+        assert_eq!(world.tiles, expected.tiles);
+        // the electron made it into the second chunk
+        assert_eq!(world.get_cell(41, 5), Some(CellState::Alive));
+
+        // rings that didn't change aren't sent, and stay as they were
+        let mut world = two_chunks();
+        let mut expected = two_chunks();
+        expected.tiles = step_all(&expected.tiles);
+        world.replay(1, &[]);
+        assert_eq!(world.tiles, expected.tiles);
+    }
+
+    #[test]
+    fn panning() {
+        let mut world = two_chunks();
+        world.pan(CHUNK_SIZE, -CHUNK_SIZE, 2 * CHUNK_SIZE, 2 * CHUNK_SIZE);
+        assert_eq!((world.x, world.y), (CHUNK_SIZE, -CHUNK_SIZE));
+        assert_eq!(world.tiles.len(), 2 * CHUNK_SIZE as usize);
+        assert_eq!(world.tiles[0].len(), 2 * CHUNK_SIZE as usize);
+        // the part of the wire still in view is kept, and the rest is empty until sent
+        for x in CHUNK_SIZE..45 {
+            assert_eq!(world.get_cell(x, 5), Some(CellState::Wire), "{}", x);
+        }
+        assert_eq!(world.get_cell(31, 5), None);
+        assert_eq!(world.get_cell(45, 5), Some(CellState::Empty));
+        assert_eq!(world.get_cell(CHUNK_SIZE, -1), Some(CellState::Empty));
+        let cells = world.tiles.concat();
+        let wire = cells.iter().filter(|&&c| c != CellState::Empty).count();
+        assert_eq!(wire, 45 - CHUNK_SIZE as usize);
+    }
+
+    #[test]
+    fn checksums() {
+        let mut world = two_chunks();
+        let checksum = proto::checksum(&world.tiles);
+        assert!(world.matches(checksum));
+        // as if the client stepped a cell differently
+        world.set_cell(30, 5, CellState::Alive);
+        assert!(!world.matches(checksum));
+    }
+}
@@ -1,8 +1,10 @@
 use serde::{Deserialize, Serialize};
 
+pub mod pack;
 pub mod proto;
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[repr(u8)]
 pub enum CellState {
     Alive,
@@ -11,8 +13,75 @@ pub enum CellState {
     Wire,
 }
 
+impl CellState {
+    /// A two bit code for the state, with `Empty` as zero
+    pub fn to_bits(self) -> u8 {
+        match self {
+            CellState::Empty => 0,
+            CellState::Wire => 1,
+            CellState::Alive => 2,
+            CellState::Dead => 3,
+        }
+    }
+
+    /// Inverse of `to_bits`, ignoring all but the lowest two bits
+    pub fn from_bits(b: u8) -> CellState {
+        match b & 0b11 {
+            0 => CellState::Empty,
+            1 => CellState::Wire,
+            2 => CellState::Alive,
+            _ => CellState::Dead,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub struct Point {
     pub x: i32,
     pub y: i32,
 }
+
+/// A clockwise quarter turn count
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Rotation {
+    #[default]
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+/// Mirroring left to right, then rotating, a pattern in place
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Transform {
+    pub mirror: bool,
+    pub rotation: Rotation,
+}
+
+impl Transform {
+    /// The size of a `w` by `h` box after the transform
+    pub fn size(&self, w: i32, h: i32) -> (i32, i32) {
+        match self.rotation {
+            Rotation::R0 | Rotation::R180 => (w, h),
+            Rotation::R90 | Rotation::R270 => (h, w),
+        }
+    }
+
+    /// Move a point in a `w` by `h` box with its corner at the origin to where it ends up after
+    /// the transform, the transformed box also having its corner at the origin
+    pub fn apply(&self, p: Point, w: i32, h: i32) -> Point {
+        let x = if self.mirror { w - 1 - p.x } else { p.x };
+        let y = p.y;
+        match self.rotation {
+            Rotation::R0 => Point { x, y },
+            Rotation::R90 => Point { x: h - 1 - y, y: x },
+            Rotation::R180 => Point {
+                x: w - 1 - x,
+                y: h - 1 - y,
+            },
+            Rotation::R270 => Point { x: y, y: w - 1 - x },
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Compact byte encodings for sequences of cells.
+
+use crate::CellState;
+
+/// The most cells either decoder will produce, whatever length it's asked for
+pub const MAX_CELLS: usize = 1 << 24;
+
+/// Pack cells at two bits each, four to a byte, the first cell in the lowest bits
+pub fn pack_2bit(cells: &[CellState]) -> Vec<u8> {
+    cells
+        .chunks(4)
+        .map(|c| {
+            c.iter()
+                .enumerate()
+                .fold(0, |acc, (i, s)| acc | s.to_bits() << (i * 2))
+        })
+        .collect()
+}
+
+/// Unpack `len` cells packed by `pack_2bit`. Returns `None` if `data` has the wrong length,
+/// or `len` is over `MAX_CELLS`.
+pub fn unpack_2bit(data: &[u8], len: usize) -> Option<Vec<CellState>> {
+    if len > MAX_CELLS || data.len() != len.div_ceil(4) {
+        return None;
+    }
+    Some(
+        (0..len)
+            .map(|i| CellState::from_bits(data[i / 4] >> ((i % 4) * 2)))
+            .collect(),
+    )
+}
+
+const MAX_RUN: usize = 64;
+
+/// Run-length code cells, one byte per run of up to 64 identical cells: the state in the
+/// lowest two bits and the run length minus one in the rest
+pub fn rle_encode(cells: &[CellState]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < cells.len() {
+        let start = i;
+        while i < cells.len() && i - start < MAX_RUN && cells[i] == cells[start] {
+            i += 1;
+        }
+        out.push(((i - start - 1) as u8) << 2 | cells[start].to_bits());
+    }
+    out
+}
+
+/// Decode the output of `rle_encode`. Returns `None` unless it decodes to exactly `len` cells,
+/// or if `len` is over `MAX_CELLS`.
+pub fn rle_decode(data: &[u8], len: usize) -> Option<Vec<CellState>> {
+    if len > MAX_CELLS || data.len().saturating_mul(MAX_RUN) < len {
+        return None;
+    }
+    // grown as runs are read, so the other side can't make us reserve more than it sent
+    let mut out = Vec::new();
+    for &b in data {
+        let run = (b >> 2) as usize + 1;
+        if out.len() + run > len {
+            return None;
+        }
+        out.extend(std::iter::repeat_n(CellState::from_bits(b), run));
+    }
+    (out.len() == len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [CellState; 4] = [
+        CellState::Empty,
+        CellState::Wire,
+        CellState::Alive,
+        CellState::Dead,
+    ];
+
+    // cells with runs of every length from 1 to past `MAX_RUN`
+    fn sample(len: usize) -> Vec<CellState> {
+        let mut cells = Vec::new();
+        let mut run = 1;
+        while cells.len() < len {
+            let state = STATES[run % 4];
+            cells.extend(std::iter::repeat_n(state, run.min(len - cells.len())));
+            run = run % (MAX_RUN + 3) + 1;
+        }
+        cells
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        for len in [0, 1, 3, 4, 5, 1024, 4099] {
+            let cells = sample(len);
+            let packed = pack_2bit(&cells);
+            assert_eq!(packed.len(), len.div_ceil(4));
+            assert_eq!(unpack_2bit(&packed, len), Some(cells), "{} cells", len);
+        }
+        assert_eq!(pack_2bit(&STATES), [0b11_10_01_00]);
+    }
+
+    #[test]
+    fn unpack_wrong_length() {
+        let packed = pack_2bit(&sample(10));
+        assert_eq!(unpack_2bit(&packed, 13), None);
+        assert_eq!(unpack_2bit(&packed, 8), None);
+        assert_eq!(unpack_2bit(&packed[..2], 10), None);
+        assert_eq!(unpack_2bit(&[], 1), None);
+        assert_eq!(
+            unpack_2bit(&vec![0; MAX_CELLS / 4 + 1], MAX_CELLS + 4),
+            None
+        );
+        assert_eq!(unpack_2bit(&[], usize::MAX), None);
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        for len in [0, 1, 63, 64, 65, 1024, 5000] {
+            let cells = sample(len);
+            let encoded = rle_encode(&cells);
+            assert_eq!(rle_decode(&encoded, len), Some(cells), "{} cells", len);
+        }
+        let long = vec![CellState::Wire; 3 * MAX_RUN + 1];
+        assert_eq!(rle_encode(&long), [0xfd, 0xfd, 0xfd, 0x01]);
+        assert_eq!(rle_decode(&rle_encode(&long), long.len()), Some(long));
+    }
+
+    #[test]
+    fn rle_wrong_length() {
+        let encoded = rle_encode(&sample(100));
+        assert_eq!(rle_decode(&encoded, 99), None);
+        assert_eq!(rle_decode(&encoded, 101), None);
+        assert_eq!(rle_decode(&encoded[1..], 100), None);
+        assert_eq!(rle_decode(&[], 1), None);
+        // far more cells than the data could hold, or than anyone should ask for
+        assert_eq!(rle_decode(&[0xff], 1 << 40), None);
+        assert_eq!(rle_decode(&[0xff], usize::MAX), None);
+        let huge = vec![0xff; MAX_CELLS / MAX_RUN + 1];
+        assert_eq!(rle_decode(&huge, MAX_CELLS + MAX_RUN), None);
+    }
+}
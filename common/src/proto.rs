@@ -1,23 +1,466 @@
-use crate::CellState;
-use serde::{Deserialize, Serialize};
+//! The messages sent over a world's websocket.
+//!
+//! A client can send them as MessagePack in binary frames, or as JSON in text frames, and
+//! is answered in whichever its `Hello` was sent in. Enums are tagged the way serde does by
+//! default, so in JSON a message without fields is just its name, `"StartStream"`, and one
+//! with fields is an object holding them under its name, `{"SetView": {"x": 0, ...}}`. With
+//! the `schema` feature the types describe themselves as JSON schemas, which the server
+//! serves at `/protocol`.
 
+use std::fmt;
+
+use crate::{
+    pack::{pack_2bit, rle_decode, rle_encode, unpack_2bit, MAX_CELLS},
+    CellState, Transform,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// The version of the protocol, which the client and server have to agree on
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How often, in generations, a connection is sent a `Checksum` of its view
+pub const CHECKSUM_EVERY: u64 = 64;
+
+/// The checksum of a view's tiles, indexed by y then x, as sent in `Checksum`
+pub fn checksum(tiles: &[Vec<CellState>]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for row in tiles {
+        let bits: Vec<u8> = row.iter().map(|c| c.to_bits()).collect();
+        hasher.update(&bits);
+    }
+    hasher.finalize()
+}
+
+/// The width and height of a chunk. Views are made of whole chunks, so that what's sent for
+/// each chunk can be shared by everyone looking at it.
+pub const CHUNK_SIZE: i32 = 32;
+
+/// A rectangle of tiles
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+/// How the tiles of a `FullRefresh` or `Pan` are laid out, chosen per connection as the
+/// most compact one both ends support
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Encoding {
+    Plain,
+    Packed,
+    RunLength,
+}
+
+impl Encoding {
+    /// The capability saying an encoding is supported. `Plain` always is.
+    pub fn capability(self) -> Option<&'static str> {
+        match self {
+            Encoding::Plain => None,
+            Encoding::Packed => Some("packed"),
+            Encoding::RunLength => Some("run-length"),
+        }
+    }
+
+    /// The most compact encoding given in `capabilities`
+    pub fn choose(capabilities: &[String]) -> Encoding {
+        [Encoding::RunLength, Encoding::Packed]
+            .into_iter()
+            .find(|e| {
+                capabilities
+                    .iter()
+                    .any(|c| Some(c.as_str()) == e.capability())
+            })
+            .unwrap_or(Encoding::Plain)
+    }
+}
+
+/// Bytes, sent as such rather than as a list of numbers
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Bytes(pub Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        struct Visitor;
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Bytes;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("bytes")
+            }
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+                Ok(Bytes(v.to_vec()))
+            }
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                Ok(Bytes(v))
+            }
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(b) = seq.next_element()? {
+                    bytes.push(b);
+                }
+                Ok(Bytes(bytes))
+            }
+        }
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
+/// A rectangle of tiles, in one of the `Encoding`s
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum Tiles {
+    /// indexed by y then x
+    Plain(Vec<Vec<CellState>>),
+    /// `w` by `h` tiles row by row, packed by `pack_2bit`
+    Packed { w: u32, h: u32, data: Bytes },
+    /// `w` by `h` tiles row by row, coded by `rle_encode`
+    RunLength { w: u32, h: u32, data: Bytes },
+}
+
+impl Tiles {
+    /// `tiles`, indexed by y then x, in `encoding`
+    pub fn encode(tiles: Vec<Vec<CellState>>, encoding: Encoding) -> Tiles {
+        let h = tiles.len() as u32;
+        let w = tiles.first().map_or(0, |row| row.len()) as u32;
+        match encoding {
+            Encoding::Plain => Tiles::Plain(tiles),
+            Encoding::Packed => Tiles::Packed {
+                w,
+                h,
+                data: Bytes(pack_2bit(&tiles.concat())),
+            },
+            Encoding::RunLength => Tiles::RunLength {
+                w,
+                h,
+                data: Bytes(rle_encode(&tiles.concat())),
+            },
+        }
+    }
+
+    /// The width and height, as the tiles claim them to be, without decoding them
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            Tiles::Plain(tiles) => (tiles.first().map_or(0, |row| row.len()), tiles.len()),
+            Tiles::Packed { w, h, .. } | Tiles::RunLength { w, h, .. } => {
+                (*w as usize, *h as usize)
+            }
+        }
+    }
+
+    /// The tiles, indexed by y then x, or `None` if they don't add up or there would be more
+    /// than `MAX_CELLS` of them
+    pub fn decode(self) -> Option<Vec<Vec<CellState>>> {
+        // even an empty row takes room, so the height is capped on its own too
+        let size = |w: u32, h: u32| {
+            let (w, h) = (w as usize, h as usize);
+            w.checked_mul(h)
+                .filter(|&len| len <= MAX_CELLS && h <= MAX_CELLS)
+                .map(|len| (w, h, len))
+        };
+        let (w, h, cells) = match self {
+            Tiles::Plain(tiles) => return Some(tiles),
+            Tiles::Packed { w, h, data } => {
+                let (w, h, len) = size(w, h)?;
+                (w, h, unpack_2bit(&data.0, len)?)
+            }
+            Tiles::RunLength { w, h, data } => {
+                let (w, h, len) = size(w, h)?;
+                (w, h, rle_decode(&data.0, len)?)
+            }
+        };
+        Some((0..h).map(|y| cells[y * w..(y + 1) * w].to_vec()).collect())
+    }
+}
+
+/// Every message about what's in view carries the generation it's of
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum FromServer {
+    /// The answer to a `Hello` the server is happy with
+    Welcome {
+        version: u32,
+        /// the rule the world follows, `wireworld`
+        rule: String,
+        /// milliseconds per generation
+        tick_ms: u64,
+        generation: u64,
+        world: String,
+        /// the smallest rectangle holding everything in the world, if there's anything
+        bounds: Option<Rect>,
+        /// the optional parts of the protocol the server supports
+        capabilities: Vec<String>,
+    },
+    /// The answer to a `Hello` the server isn't happy with, after which it hangs up
+    Rejected { reason: String },
+    /// Everything in the view, which is grown to whole chunks
     FullRefresh {
+        generation: u64,
         x: i32,
         y: i32,
-        /// indexed by y then x
-        tiles: Vec<Vec<CellState>>,
+        tiles: Tiles,
     },
-    PartialRefresh {
-        /// the outside perimeter tiles, starting at the top left and going counter-clockwise
+    /// A chunk that was edited, to replace in full on the next `Tick`
+    Chunk {
+        generation: u64,
+        /// the top left tile
+        x: i32,
+        y: i32,
+        /// row by row
+        tiles: Vec<CellState>,
+    },
+    /// The outermost ring of a chunk, which changed, to replace on the next `Tick`
+    ChunkRing {
+        generation: u64,
+        /// the top left tile
+        x: i32,
+        y: i32,
+        /// starting at the top left and going counter-clockwise
         tiles: Vec<CellState>,
     },
+    /// A generation has passed. Step the inside of every chunk, everything but its
+    /// outermost ring, then apply the `Chunk`s and `ChunkRing`s sent since the last `Tick`.
+    /// Rings that weren't sent haven't changed.
+    Tick {
+        /// the generation this brings the client to
+        generation: u64,
+    },
+    /// `count` generations have passed at once. For each in turn, step the inside of every
+    /// chunk as for a `Tick`, then set the ring of each chunk in `rings` to its next one.
+    /// The rings of the other chunks didn't change.
+    Ticks {
+        /// the generation this brings the client to
+        generation: u64,
+        count: u32,
+        /// the top left tile of a chunk, and its ring after each generation in turn
+        rings: Vec<(i32, i32, Vec<Vec<CellState>>)>,
+    },
+    /// The connection fell behind and skipped some generations. Set the cells that changed
+    /// since the last `Tick`, with no stepping, to be at the latest generation.
+    CatchUp {
+        generation: u64,
+        /// the top left tile of the view
+        x: i32,
+        y: i32,
+        /// each cell's offset from (`x`, `y`), and what it is now
+        cells: Vec<(u16, u16, CellState)>,
+    },
+    /// The view moved or changed size, which takes effect straight away. Keep the tiles
+    /// still in view, and fill in the chunks newly in view from `chunks`.
+    Pan {
+        /// the generation the client has, which the chunks are of
+        generation: u64,
+        /// the new view, which is grown to whole chunks
+        x: i32,
+        y: i32,
+        w: i32,
+        h: i32,
+        /// the top left tile of each chunk newly in view, and its cells
+        chunks: Vec<(i32, i32, Tiles)>,
+    },
+    /// The checksum of everything in the view as of `generation`, sent now and then after
+    /// its `Tick`. If the client's view doesn't match, it's gone wrong somewhere and should
+    /// ask to `Resync`.
+    Checksum { generation: u64, checksum: u32 },
+    /// A `Request` was carried out
+    Ack { request_id: u32 },
+    /// A message was refused, or couldn't be read
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// the id of the `Request` refused, if it was one
+        request_id: Option<u32>,
+    },
+    /// The connection is now looking at its sandbox rather than the shared world, sent
+    /// whenever the sandbox's controls change
+    SandboxStatus {
+        paused: bool,
+        /// milliseconds per generation while running
+        tick_ms: u64,
+        generation: u64,
+    },
+    /// The connection is back to looking at the shared world
+    SandboxClosed,
+    /// `msg`, which is about the view called `view` rather than the main one
+    InView { view: String, msg: Box<FromServer> },
+}
+
+/// Why a message was refused
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum ErrorCode {
+    /// it couldn't be read, or doesn't make sense to send
+    BadMessage,
+    /// it's for a sandbox, but there isn't one open
+    NoSandbox,
+    /// what it asks for can't be done
+    Invalid,
+    /// it's an edit for a generation that's already been stepped past
+    TooLate,
 }
 
+/// `Hello` has to come first
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum FromClient {
-    ModifyCell { x: i32, y: i32, cell: CellState },
-    SetView { x: i32, y: i32, w: i32, h: i32 },
+    Hello {
+        /// `PROTOCOL_VERSION` as the client knows it
+        version: u32,
+        /// the optional parts of the protocol the client supports
+        capabilities: Vec<String>,
+    },
+    /// `request`, to be answered with an `Ack` or `Error` with the same id once it's been
+    /// dealt with. Any message but `Hello` can be sent like this.
+    Request {
+        id: u32,
+        request: Box<FromClient>,
+    },
+    ModifyCell {
+        x: i32,
+        y: i32,
+        cell: CellState,
+    },
+    /// Make `edit`, any of the edits, to `generation` just before it's stepped, rather than
+    /// to whichever generation the edit reaches the server in. It's refused with `TooLate`
+    /// if that generation has already been stepped past. As a `Request`, it isn't answered
+    /// until it's been made.
+    At {
+        generation: u64,
+        edit: Box<FromClient>,
+    },
+    /// Set each of `cells`, given as (x, y, what to set it to). Like the other edits of more
+    /// than one cell, it's done all at once, between two generations.
+    ModifyCells {
+        cells: Vec<(i32, i32, CellState)>,
+    },
+    /// Set every cell in `rect` to `cell`
+    FillRect {
+        rect: Rect,
+        cell: CellState,
+    },
+    /// Paste `pattern`, empty cells and all, mirrored and turned by `transform`, with its
+    /// top left at (`x`, `y`)
+    PastePattern {
+        x: i32,
+        y: i32,
+        pattern: Tiles,
+        #[serde(default)]
+        transform: Transform,
+    },
+    /// Move the main view
+    SetView {
+        x: i32,
+        y: i32,
+        w: i32,
+        h: i32,
+    },
+    /// Move the view called `name`, opening it if there isn't one. Each view is kept up to
+    /// date on its own, and messages about any but the main one come in `InView`.
+    SetNamedView {
+        name: String,
+        x: i32,
+        y: i32,
+        w: i32,
+        h: i32,
+    },
+    /// Stop looking at the view called `name`
+    CloseView {
+        name: String,
+    },
     StartStream,
+    /// Copy `region` of the shared world, or all of it, into a private sandbox, replacing
+    /// any sandbox already open. Until it's closed, edits and refreshes are of the sandbox.
+    Fork {
+        region: Option<Rect>,
+    },
+    /// Stop the sandbox's clock
+    Pause,
+    Resume,
+    /// Advance a paused sandbox
+    Step {
+        generations: u32,
+    },
+    SetSpeed {
+        tick_ms: u64,
+    },
+    /// Make the edits done in the sandbox to the shared world too, and close the sandbox
+    Merge,
+    /// Close the sandbox, throwing it away
+    LeaveSandbox,
+    /// Send everything in every view again, because what the client has doesn't match
+    Resync,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(w: usize, h: usize) -> Vec<Vec<CellState>> {
+        (0..h)
+            .map(|y| {
+                (0..w)
+                    .map(|x| CellState::from_bits((x / 3 + y) as u8))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tiles_round_trip() {
+        for (w, h) in [(0, 0), (1, 1), (7, 3), (64, 64), (100, 2)] {
+            for encoding in [Encoding::Plain, Encoding::Packed, Encoding::RunLength] {
+                let tiles = sample(w, h);
+                let decoded = Tiles::encode(tiles.clone(), encoding).decode();
+                assert_eq!(decoded, Some(tiles), "{}x{} {:?}", w, h, encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_malformed() {
+        let data = |tiles: Tiles| match tiles {
+            Tiles::Packed { data, .. } | Tiles::RunLength { data, .. } => data,
+            Tiles::Plain(_) => unreachable!(),
+        };
+        let packed = data(Tiles::encode(sample(8, 8), Encoding::Packed));
+        let rle = data(Tiles::encode(sample(8, 8), Encoding::RunLength));
+        for tiles in [
+            Tiles::Packed {
+                w: 8,
+                h: 9,
+                data: packed.clone(),
+            },
+            Tiles::Packed {
+                w: u32::MAX,
+                h: u32::MAX,
+                data: packed,
+            },
+            Tiles::RunLength {
+                w: 9,
+                h: 8,
+                data: rle.clone(),
+            },
+            Tiles::RunLength {
+                w: 1 << 20,
+                h: 1 << 20,
+                data: Bytes(vec![0xff; 16]),
+            },
+            Tiles::RunLength {
+                w: 0,
+                h: u32::MAX,
+                data: Bytes(Vec::new()),
+            },
+        ] {
+            assert_eq!(tiles.decode(), None);
+        }
+    }
 }
@@ -4,7 +4,7 @@ use wire_universe_server::world::World;
 
 fn simulate_computer(cycles: u64, mut world: World) {
     for _ in 0..cycles {
-        world.step();
+        world.step().unwrap();
     }
 }
 
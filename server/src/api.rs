@@ -0,0 +1,293 @@
+//! The HTTP API for managing the hosted worlds.
+//!
+//! ```text
+//! GET    /worlds          every world, as a list of `WorldInfo`
+//! POST   /worlds          create a world from a `CreateWorld`, returning its `WorldInfo`
+//! GET    /worlds/<name>   one world's `WorldInfo`
+//! DELETE /worlds/<name>   stop a world created through the API and delete its data
+//! GET    /protocol        the websocket protocol's JSON schemas, as a `Protocol`
+//! ```
+//!
+//! Bodies are JSON. Failures are reported as `{"error": "<message>"}`. Creating a world whose
+//! name is taken, or when as many worlds as allowed are hosted, fails with 409 Conflict.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use schemars::{schema::RootSchema, schema_for};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use wire_universe::proto::{FromClient, FromServer, PROTOCOL_VERSION};
+
+use crate::{
+    config::WorldConfig,
+    worlds::{check_name, Hosted, Refused},
+    AppState,
+};
+
+#[derive(Serialize)]
+pub struct WorldInfo {
+    pub name: String,
+    pub generation: u64,
+    pub tick_ms: u64,
+    /// how many clients are connected
+    pub clients: usize,
+    /// whether the world was created through the API, and so can be deleted through it
+    pub created: bool,
+    /// `[x, y, w, h]` of the smallest rectangle containing every cell, if there are any
+    pub bounds: Option<[i32; 4]>,
+}
+
+impl WorldInfo {
+    fn of(hosted: &Hosted) -> WorldInfo {
+        let world = hosted.handle.snapshot();
+        WorldInfo {
+            name: hosted.handle.name.clone(),
+            generation: world.generation(),
+            tick_ms: hosted.handle.opts.tick.as_millis() as u64,
+            clients: hosted.handle.clients(),
+            created: hosted.created,
+            bounds: world.bounds().map(|(x, y, w, h)| [x, y, w, h]),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateWorld {
+    pub name: String,
+    /// start as a copy of this world, as it is now, instead of empty
+    pub copy_from: Option<String>,
+    pub tick: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            error: String,
+        }
+        (self.0, Json(Body { error: self.1 })).into_response()
+    }
+}
+
+fn not_found(name: &str) -> ApiError {
+    ApiError(
+        StatusCode::NOT_FOUND,
+        format!("There is no world called `{}'", name),
+    )
+}
+
+fn internal(e: anyhow::Error) -> ApiError {
+    ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+}
+
+// a refusal is the client's to deal with, anything else went wrong here
+fn not_created(e: anyhow::Error) -> ApiError {
+    match e.downcast_ref::<Refused>() {
+        Some(refused) => ApiError(StatusCode::CONFLICT, refused.to_string()),
+        None => internal(e),
+    }
+}
+
+#[derive(Serialize)]
+pub struct Protocol {
+    pub version: u32,
+    /// the schema of a message from a client
+    pub from_client: RootSchema,
+    /// the schema of a message from the server
+    pub from_server: RootSchema,
+}
+
+pub(crate) async fn protocol() -> Json<Protocol> {
+    Json(Protocol {
+        version: PROTOCOL_VERSION,
+        from_client: schema_for!(FromClient),
+        from_server: schema_for!(FromServer),
+    })
+}
+
+pub(crate) async fn list_worlds(State(state): State<AppState>) -> Json<Vec<WorldInfo>> {
+    Json(
+        state
+            .worlds
+            .list()
+            .iter()
+            .map(|hosted| WorldInfo::of(hosted))
+            .collect(),
+    )
+}
+
+pub(crate) async fn get_world(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<WorldInfo>, ApiError> {
+    let hosted = state.worlds.get(&name).ok_or_else(|| not_found(&name))?;
+    Ok(Json(WorldInfo::of(&hosted)))
+}
+
+pub(crate) async fn create_world(
+    State(state): State<AppState>,
+    Json(request): Json<CreateWorld>,
+) -> Result<(StatusCode, Json<WorldInfo>), ApiError> {
+    let bad_request = |e: anyhow::Error| ApiError(StatusCode::BAD_REQUEST, format!("{:#}", e));
+    check_name(&request.name).map_err(bad_request)?;
+    if state.worlds.get(&request.name).is_some() {
+        return Err(ApiError(
+            StatusCode::CONFLICT,
+            format!("There is already a world called `{}'", request.name),
+        ));
+    }
+    let seed = match &request.copy_from {
+        Some(from) => {
+            let hosted = state.worlds.get(from).ok_or_else(|| not_found(from))?;
+            let mut world = (*hosted.handle.snapshot()).clone();
+            task::block_in_place(|| world.detach()).map_err(internal)?;
+            Some(world)
+        }
+        None => None,
+    };
+    let config = WorldConfig {
+        tick: request.tick,
+        ..Default::default()
+    };
+    config.clone().options().map_err(bad_request)?;
+    let hosted = state
+        .worlds
+        .create(&request.name, config, seed)
+        .await
+        .map_err(not_created)?;
+    Ok((StatusCode::CREATED, Json(WorldInfo::of(&hosted))))
+}
+
+pub(crate) async fn delete_world(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let hosted = state.worlds.get(&name).ok_or_else(|| not_found(&name))?;
+    if !hosted.created {
+        return Err(ApiError(
+            StatusCode::CONFLICT,
+            format!("`{}' is configured, so it can't be deleted", name),
+        ));
+    }
+    state.worlds.delete(&name).await.map_err(internal)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{host::WorldOptions, worlds::Worlds, worlds::DEFAULT_WORLD};
+
+    fn state(max_worlds: usize) -> AppState {
+        let configured = vec![(DEFAULT_WORLD.to_owned(), WorldOptions::default())];
+        AppState {
+            worlds: Arc::new(Worlds::start(configured, None, max_worlds).unwrap()),
+        }
+    }
+
+    async fn create(
+        state: &AppState,
+        name: &str,
+        copy_from: Option<&str>,
+    ) -> Result<WorldInfo, ApiError> {
+        let request = CreateWorld {
+            name: name.to_owned(),
+            copy_from: copy_from.map(str::to_owned),
+            tick: Some(50),
+        };
+        let (status, Json(info)) = create_world(State(state.clone()), Json(request)).await?;
+        assert_eq!(status, StatusCode::CREATED);
+        Ok(info)
+    }
+
+    fn status<T>(result: Result<T, ApiError>) -> StatusCode {
+        match result {
+            Ok(_) => panic!("succeeded"),
+            Err(e) => e.0,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn creating_and_deleting() {
+        let state = state(3);
+        let info = create(&state, "demo", None).await.unwrap();
+        assert_eq!(info.name, "demo");
+        assert_eq!(info.tick_ms, 50);
+        assert!(info.created);
+        let info = create(&state, "copy", Some(DEFAULT_WORLD)).await.unwrap();
+        assert_eq!(info.generation, 0);
+
+        let Json(info) = get_world(State(state.clone()), Path("demo".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(info.name, "demo");
+        let Json(all) = list_worlds(State(state.clone())).await;
+        let names: Vec<_> = all.iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, ["copy", DEFAULT_WORLD, "demo"]);
+
+        let deleted = delete_world(State(state.clone()), Path("demo".to_owned())).await;
+        assert_eq!(deleted.unwrap(), StatusCode::NO_CONTENT);
+        let missing = get_world(State(state.clone()), Path("demo".to_owned())).await;
+        assert_eq!(status(missing), StatusCode::NOT_FOUND);
+        state.worlds.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failures() {
+        let state = state(2);
+        assert_eq!(
+            status(create(&state, "a b", None).await),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(create(&state, "demo", Some("nowhere")).await),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(create(&state, DEFAULT_WORLD, None).await),
+            StatusCode::CONFLICT
+        );
+        create(&state, "demo", None).await.unwrap();
+        // too many worlds
+        let full = create(&state, "more", None).await;
+        assert_eq!(status(full), StatusCode::CONFLICT);
+        let configured = delete_world(State(state.clone()), Path(DEFAULT_WORLD.to_owned())).await;
+        assert_eq!(status(configured), StatusCode::CONFLICT);
+        state.worlds.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn racing_over_a_name() {
+        let state = state(8);
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move { create(&state, "demo", None).await.err().map(|e| e.0) })
+            })
+            .collect();
+        let mut statuses = Vec::new();
+        for task in tasks {
+            statuses.push(task.await.unwrap());
+        }
+        statuses.sort();
+        // whether the loser got past the first check or not, it's a conflict
+        assert_eq!(statuses, [None, Some(StatusCode::CONFLICT)]);
+        let taken = not_created(Refused::Taken("demo".to_owned()).into());
+        assert_eq!(taken.0, StatusCode::CONFLICT);
+        assert_eq!(
+            not_created(anyhow::anyhow!("disk full")).0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        state.worlds.stop().await;
+    }
+}
@@ -0,0 +1,202 @@
+//! Periodic snapshots of the running world, so it survives restarts and crashes.
+//!
+//! Autosaves are written as `autosave-<millis>-g<generation>.wus` in their own directory,
+//! where `millis` is the time of the save since the unix epoch. Saves are ordered by the
+//! generation they hold, so a clock stepping backwards never makes an old save look new,
+//! with the time only breaking ties. Each is written to a
+//! temporary file and renamed into place, so a crash mid-save never leaves a partial
+//! snapshot behind under a real name.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::world::World;
+
+#[derive(Clone, Debug)]
+pub struct AutosaveOptions {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// how many of the newest autosaves to keep around
+    pub keep: usize,
+}
+
+const PREFIX: &str = "autosave-";
+const EXTENSION: &str = ".wus";
+const TEMP_EXTENSION: &str = ".wus.tmp";
+
+// the generation in an autosave's name, if it is one
+fn generation(name: &str) -> Option<u64> {
+    let stem = name.strip_prefix(PREFIX)?.strip_suffix(EXTENSION)?;
+    let (_, generation) = stem.rsplit_once("-g")?;
+    generation.parse().ok()
+}
+
+// autosaves in `dir`, oldest first
+fn list(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut saves = Vec::new();
+    for entry in fs::read_dir(dir).context(format!("Failed to list {}", dir.display()))? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if let Some(generation) = generation(name) {
+            saves.push((generation, path));
+        }
+    }
+    // the timestamp is zero padded, so names sort by age within a generation
+    saves.sort();
+    Ok(saves.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Atomically write an autosave of `world` into the directory, then delete all but the
+/// newest `keep` autosaves
+pub fn save(opts: &AutosaveOptions, world: &World) -> Result<PathBuf> {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let name = format!("{}{:016}-g{}", PREFIX, millis, world.generation());
+    let temp = opts.dir.join(name.clone() + TEMP_EXTENSION);
+    let path = opts.dir.join(name + EXTENSION);
+    (|| -> Result<()> {
+        let mut file = File::create(&temp)?;
+        let mut out = BufWriter::new(&mut file);
+        world.write_snapshot(&mut out, true)?;
+        out.flush()?;
+        drop(out);
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+        // make sure the rename itself is on disk
+        #[cfg(unix)]
+        File::open(&opts.dir)?.sync_all()?;
+        Ok(())
+    })()
+    .context(format!("Failed to write autosave {}", path.display()))?;
+
+    let saves = list(&opts.dir)?;
+    for old in &saves[..saves.len().saturating_sub(opts.keep.max(1))] {
+        fs::remove_file(old).context(format!("Failed to remove {}", old.display()))?;
+    }
+    Ok(path)
+}
+
+/// Load the newest autosave that reads back correctly, skipping any that don't, and clear
+/// out temporary files left by interrupted saves. Creates the directory if needed.
+pub fn load_latest(opts: &AutosaveOptions) -> Result<Option<(PathBuf, World)>> {
+    fs::create_dir_all(&opts.dir).context(format!("Failed to create {}", opts.dir.display()))?;
+    for entry in fs::read_dir(&opts.dir)? {
+        let path = entry?.path();
+        if path.to_str().is_some_and(|p| p.ends_with(TEMP_EXTENSION)) {
+            _ = fs::remove_file(path);
+        }
+    }
+    for path in list(&opts.dir)?.into_iter().rev() {
+        match World::load_snapshot(&path) {
+            Ok(world) => return Ok(Some((path, world))),
+            Err(e) => log::warn!("Skipping bad autosave: {:#}", e),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sorted_cells, temp_dir};
+    use wire_universe::{CellState, Point};
+
+    fn options(dir: &Path, keep: usize) -> AutosaveOptions {
+        AutosaveOptions {
+            dir: dir.to_owned(),
+            interval: Duration::from_secs(60),
+            keep,
+        }
+    }
+
+    fn world(generation: u64) -> World {
+        let mut world = World::new();
+        world.set_generation(generation);
+        world
+            .set_tile(Point { x: 3, y: -7 }, CellState::Wire)
+            .unwrap();
+        world
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = temp_dir(&[]);
+        let opts = options(dir.path(), 3);
+        assert!(load_latest(&opts).unwrap().is_none());
+        let path = save(&opts, &world(12)).unwrap();
+        assert!(path.to_str().unwrap().ends_with("-g12.wus"));
+        let (loaded, read) = load_latest(&opts).unwrap().unwrap();
+        assert_eq!(loaded, path);
+        assert_eq!(read.generation(), 12);
+        assert_eq!(sorted_cells(&read), sorted_cells(&world(12)));
+    }
+
+    #[test]
+    fn prunes_to_keep() {
+        let dir = temp_dir(&[]);
+        let opts = options(dir.path(), 2);
+        for generation in 1..=4 {
+            save(&opts, &world(generation)).unwrap();
+        }
+        let names = names(dir.path());
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names[0].ends_with("-g3.wus") && names[1].ends_with("-g4.wus"));
+    }
+
+    #[test]
+    fn ordered_by_generation() {
+        // the later save by the clock holds the older world
+        let dir = temp_dir(&[]);
+        let newer = format!("{}{:016}-g900.wus", PREFIX, 1);
+        let older = format!("{}{:016}-g80.wus", PREFIX, 2);
+        let mut data = Vec::new();
+        world(900).write_snapshot(&mut data, true).unwrap();
+        fs::write(dir.path().join(&newer), &data).unwrap();
+        data.clear();
+        world(80).write_snapshot(&mut data, true).unwrap();
+        fs::write(dir.path().join(&older), &data).unwrap();
+
+        let opts = options(dir.path(), 2);
+        let (_, read) = load_latest(&opts).unwrap().unwrap();
+        assert_eq!(read.generation(), 900);
+        save(&opts, &world(1000)).unwrap();
+        let names = names(dir.path());
+        assert!(!names.contains(&older), "{:?}", names);
+        assert!(names.contains(&newer), "{:?}", names);
+    }
+
+    #[test]
+    fn skips_bad_saves_and_temporaries() {
+        let dir = temp_dir(&[]);
+        let opts = options(dir.path(), 5);
+        let good = save(&opts, &world(5)).unwrap();
+        let temp = format!("{}{:016}-g8{}", PREFIX, 0, TEMP_EXTENSION);
+        let bad = format!("{}{:016}-g7.wus", PREFIX, 0);
+        fs::write(dir.path().join(&temp), "partial").unwrap();
+        fs::write(dir.path().join(&bad), "not a snapshot").unwrap();
+
+        let (loaded, read) = load_latest(&opts).unwrap().unwrap();
+        assert_eq!(loaded, good);
+        assert_eq!(read.generation(), 5);
+        let names = names(dir.path());
+        assert!(!names.contains(&temp), "{:?}", names);
+        assert!(names.contains(&bad), "{:?}", names);
+    }
+}
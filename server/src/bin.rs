@@ -1,4 +1,407 @@
+use std::{fs::File, io::BufWriter, path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use wire_universe::Point;
+use wire_universe_server::{
+    config::{AutosaveConfig, Config, DatabaseConfig, PagingConfig, WorldConfig},
+    export::{render_animation, render_svg, AnimationFormat, AnimationOptions, SvgOptions},
+    journal::{self, JournalReader},
+    serve,
+    store::SqliteStore,
+    wi::{load_wi, WiOptions},
+    world::{paging::chunk_key, World},
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Shared wireworld server",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (the default)
+    Serve(Box<ServeArgs>),
+    /// Render a region of a world over several generations as an animated GIF or APNG
+    Animate(AnimateArgs),
+    /// Render a region of a world as an SVG image
+    Svg(SvgArgs),
+    /// Check a .wi file for problems
+    Check(CheckArgs),
+    /// Replay a journal on top of the world it started from, verifying its checkpoints
+    Replay(ReplayArgs),
+    /// List the chunks in a world database
+    Chunks(ChunksArgs),
+}
+
+/// Settings given here override those in the config file. Those for a world are for the
+/// default world.
+#[derive(Args)]
+struct ServeArgs {
+    /// TOML file of settings
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// World to start with: a .wi file, .wus snapshot or .toml manifest [default: ./primes.wi]
+    #[arg(long)]
+    world: Option<PathBuf>,
+    /// Address and port to listen on [default: 0.0.0.0:3000]
+    #[arg(long)]
+    bind: Option<String>,
+    /// Directory of files to serve over HTTP [default: assets]
+    #[arg(long)]
+    assets: Option<PathBuf>,
+    /// Milliseconds per generation [default: 100]
+    #[arg(long)]
+    tick: Option<u64>,
+    /// One of off, error, warn, info, debug or trace; RUST_LOG overrides it [default: info]
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Directory to keep worlds created through the HTTP API in
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Most worlds to host at once, past which creating more is refused [default: 64]
+    #[arg(long)]
+    max_worlds: Option<usize>,
+    /// Directory to save the world in periodically and on shutdown, and to resume from
+    #[arg(long)]
+    autosave_dir: Option<PathBuf>,
+    /// Seconds between autosaves [default: 60]
+    #[arg(long)]
+    autosave_interval: Option<u64>,
+    /// Number of autosaves to keep [default: 5]
+    #[arg(long)]
+    autosave_keep: Option<usize>,
+    /// File to append every edit to, replayed on startup
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    /// Directory to page quiet parts of the world out to, when it grows past the memory budget
+    #[arg(long)]
+    page_dir: Option<PathBuf>,
+    /// Number of cells to keep in memory before paging out [default: 10000000]
+    #[arg(long)]
+    memory_budget: Option<usize>,
+    /// Generations a chunk stays in memory after it was last viewed or active [default: 600]
+    #[arg(long)]
+    page_cold_after: Option<u64>,
+    /// SQLite database to keep the world in, resumed from if it holds one
+    #[arg(long)]
+    database: Option<PathBuf>,
+    /// Seconds between writing changed chunks to the database [default: 10]
+    #[arg(long)]
+    database_interval: Option<u64>,
+}
+
+impl ServeArgs {
+    fn config(self) -> Result<Config> {
+        let file = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        let world = WorldConfig {
+            world: self.world,
+            tick: self.tick,
+            journal: self.journal,
+            autosave: AutosaveConfig {
+                dir: self.autosave_dir,
+                interval: self.autosave_interval,
+                keep: self.autosave_keep,
+            },
+            paging: PagingConfig {
+                dir: self.page_dir,
+                memory_budget: self.memory_budget,
+                cold_after: self.page_cold_after,
+            },
+            database: DatabaseConfig {
+                path: self.database,
+                interval: self.database_interval,
+            },
+        };
+        let args = Config {
+            bind: self.bind,
+            assets: self.assets,
+            log_level: self.log_level,
+            data_dir: self.data_dir,
+            max_worlds: self.max_worlds,
+            world,
+            ..Default::default()
+        };
+        Ok(args.or(file))
+    }
+}
+
+async fn run_server(args: ServeArgs) -> Result<()> {
+    let config = args.config()?;
+    // the level is for our messages; other crates only get a say about problems
+    let level = config.log_level().unwrap_or(log::LevelFilter::Info);
+    env_logger::Builder::new()
+        .filter_level(level.min(log::LevelFilter::Warn))
+        .filter_module("wire_universe_server", level)
+        .parse_default_env()
+        .init();
+    serve(config.serve_options()?).await
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum FormatArg {
+    Gif,
+    Apng,
+}
+
+/// A rectangle of the world; defaults to the bounds of the world's contents
+#[derive(Args)]
+struct RegionArgs {
+    #[arg(long, allow_hyphen_values = true)]
+    x: Option<i32>,
+    #[arg(long, allow_hyphen_values = true)]
+    y: Option<i32>,
+    #[arg(long)]
+    width: Option<i32>,
+    #[arg(long)]
+    height: Option<i32>,
+}
+
+impl RegionArgs {
+    fn is_empty(&self) -> bool {
+        self.x.is_none() && self.y.is_none() && self.width.is_none() && self.height.is_none()
+    }
+
+    fn resolve(&self, world: &World) -> (i32, i32, i32, i32) {
+        let (bx, by, bw, bh) = world.bounds().unwrap_or((0, 0, 1, 1));
+        (
+            self.x.unwrap_or(bx),
+            self.y.unwrap_or(by),
+            self.width.unwrap_or(bw),
+            self.height.unwrap_or(bh),
+        )
+    }
+}
+
+#[derive(Args)]
+struct AnimateArgs {
+    /// World to simulate: a .wi file, .wus snapshot or .toml manifest
+    input: PathBuf,
+    /// Where to write the animation
+    output: PathBuf,
+    #[command(flatten)]
+    region: RegionArgs,
+    /// Number of frames, one per generation
+    #[arg(short, long, default_value_t = 50)]
+    generations: u32,
+    /// Delay between frames in milliseconds
+    #[arg(short, long, default_value_t = 100)]
+    delay: u64,
+    /// Pixels per cell
+    #[arg(short, long, default_value_t = 4)]
+    scale: u32,
+    /// Output format; guessed from the output extension if not given
+    #[arg(short, long, value_enum)]
+    format: Option<FormatArg>,
+}
+
+fn animate(args: AnimateArgs) -> Result<()> {
+    let format = match args.format {
+        Some(FormatArg::Gif) => AnimationFormat::Gif,
+        Some(FormatArg::Apng) => AnimationFormat::Apng,
+        None => match args.output.extension().and_then(|e| e.to_str()) {
+            Some("gif") => AnimationFormat::Gif,
+            Some("png" | "apng") => AnimationFormat::Apng,
+            _ => Err(anyhow!(
+                "Can't guess format of {}, pass --format",
+                args.output.display()
+            ))?,
+        },
+    };
+    let world = World::load(&args.input)?;
+    let (x, y, w, h) = args.region.resolve(&world);
+    let opts = AnimationOptions {
+        generations: args.generations,
+        frame_delay: Duration::from_millis(args.delay),
+        scale: args.scale,
+    };
+    let out = File::create(&args.output)
+        .context(format!("Failed to create {}", args.output.display()))?;
+    render_animation(&world, x, y, w, h, format, &opts, BufWriter::new(out))
+}
+
+#[derive(Args)]
+struct SvgArgs {
+    /// World to render: a .wi file, .wus snapshot or .toml manifest
+    input: PathBuf,
+    /// Where to write the image
+    output: PathBuf,
+    #[command(flatten)]
+    region: RegionArgs,
+    /// Size of a cell in SVG units
+    #[arg(short, long, default_value_t = 10)]
+    scale: u32,
+    /// Draw lines between cells
+    #[arg(long)]
+    grid: bool,
+    /// Label coordinates along the top and left edges
+    #[arg(long)]
+    rulers: bool,
+}
+
+fn svg(args: SvgArgs) -> Result<()> {
+    let world = World::load(&args.input)?;
+    let (x, y, w, h) = args.region.resolve(&world);
+    let opts = SvgOptions {
+        scale: args.scale,
+        grid: args.grid,
+        rulers: args.rulers,
+    };
+    let out = File::create(&args.output)
+        .context(format!("Failed to create {}", args.output.display()))?;
+    render_svg(&world, x, y, w, h, &opts, BufWriter::new(out))
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    input: PathBuf,
+    /// Reject characters that don't stand for a cell
+    #[arg(long)]
+    strict: bool,
+    /// Read short rows and missing rows as empty
+    #[arg(long)]
+    pad: bool,
+}
+
+fn check(args: CheckArgs) -> Result<()> {
+    let opts = WiOptions {
+        strict: args.strict,
+        pad_short_rows: args.pad,
+    };
+    let parse = load_wi(&args.input, &opts)?;
+    for w in &parse.warnings {
+        println!("{}: warning: {}", args.input.display(), w);
+    }
+    println!(
+        "{}: {} cells, {} warnings",
+        args.input.display(),
+        parse.world.cells().count(),
+        parse.warnings.len()
+    );
+    Ok(())
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    /// The world the journal starts from: a .wi file, .wus snapshot or .toml manifest
+    base: PathBuf,
+    journal: PathBuf,
+    /// Generation to stop at; defaults to the last one in the journal
+    #[arg(long)]
+    until: Option<u64>,
+    /// State hash, in hex, the world must end up with
+    #[arg(long, value_parser = |s: &str| u64::from_str_radix(s, 16))]
+    expect: Option<u64>,
+    /// Save the resulting world as a .wus snapshot
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+fn replay(args: ReplayArgs) -> Result<()> {
+    let mut world = World::load(&args.base)?;
+    if let Some(until) = args.until {
+        ensure!(
+            until >= world.generation(),
+            "Base world is already at generation {}",
+            world.generation()
+        );
+    }
+    let mut entries = JournalReader::open(&args.journal)?;
+    let report = journal::replay(&mut world, &mut entries, args.until)?;
+    if entries.truncated {
+        eprintln!("warning: the last record in the journal is incomplete");
+    }
+    let hash = world.state_hash();
+    println!(
+        "generation {}, state hash {:016x}, {} edits applied, {} checkpoints verified",
+        world.generation(),
+        hash,
+        report.edits,
+        report.checkpoints
+    );
+    if let Some(path) = args.output {
+        world.save_snapshot(&path)?;
+    }
+    if let Some(expect) = args.expect {
+        ensure!(
+            hash == expect,
+            "State hash mismatch: expected {:016x}, got {:016x}",
+            expect,
+            hash
+        );
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct ChunksArgs {
+    database: PathBuf,
+    /// Only list chunks written after this generation
+    #[arg(long)]
+    since: Option<u64>,
+    /// Only list chunks overlapping this region; unbounded where not given
+    #[command(flatten)]
+    region: RegionArgs,
+}
+
+fn chunks(args: ChunksArgs) -> Result<()> {
+    let store = SqliteStore::open(&args.database)?;
+    let Some(generation) = store.generation()? else {
+        bail!("{} doesn't hold a world", args.database.display());
+    };
+    let (x, y) = (
+        args.region.x.unwrap_or(i32::MIN),
+        args.region.y.unwrap_or(i32::MIN),
+    );
+    let far = |start: i32, len: Option<i32>| match len {
+        Some(len) => start.saturating_add(len.max(1) - 1),
+        None => i32::MAX,
+    };
+    let from = chunk_key(Point { x, y });
+    let to = chunk_key(Point {
+        x: far(x, args.region.width),
+        y: far(y, args.region.height),
+    });
+    let chunks = match args.since {
+        Some(since) if args.region.is_empty() => store.chunks_modified_since(since)?,
+        since => store
+            .chunks_in(from, to)?
+            .into_iter()
+            .filter(|c| since.is_none_or(|since| c.generation > since))
+            .collect(),
+    };
+    println!("# database at generation {}", generation);
+    println!("# x y generation active");
+    for c in &chunks {
+        println!(
+            "{} {} {} {}",
+            c.key.0, c.key.1, c.generation, c.active as u8
+        );
+    }
+    println!("# {} chunks", chunks.len());
+    Ok(())
+}
+
 #[tokio::main]
-async fn main() {
-    wire_universe_server::serve().await
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Serve(Box::new(cli.serve))) {
+        Command::Serve(args) => run_server(*args).await,
+        Command::Animate(args) => animate(args),
+        Command::Svg(args) => svg(args),
+        Command::Check(args) => check(args),
+        Command::Replay(args) => replay(args),
+        Command::Chunks(args) => chunks(args),
+    }
 }
@@ -0,0 +1,515 @@
+//! Server settings, from a TOML file and the command line.
+//!
+//! Every setting is optional, and the command line takes precedence over the file. Settings
+//! at the top level are for the default world, served at `/ws`; more worlds each get a
+//! `[worlds.<name>]` table of the same settings, and are served at `/ws/<name>`:
+//!
+//! ```toml
+//! bind = "0.0.0.0:3000"
+//! assets = "assets"            # directory of files served over HTTP
+//! log_level = "info"           # off, error, warn, info, debug or trace
+//! data_dir = "worlds"          # where worlds created while running are kept
+//! max_worlds = 64              # worlds hosted at once, past which no more can be created
+//!
+//! world = "primes.wi"          # .wi, .wus or .toml to start from
+//! tick = 100                   # milliseconds per generation
+//! journal = "world.journal"
+//!
+//! [autosave]
+//! dir = "saves"
+//! interval = 60                # seconds
+//! keep = 5
+//!
+//! [paging]
+//! dir = "pages"
+//! memory_budget = 10000000     # cells
+//! cold_after = 600             # generations
+//!
+//! [database]
+//! path = "world.db"
+//! interval = 10                # seconds
+//!
+//! [worlds.demo]
+//! world = "demo.toml"          # worlds other than the default start empty without one
+//! tick = 250
+//! ```
+//!
+//! Relative paths in a file are relative to the file.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    autosave::AutosaveOptions,
+    host::WorldOptions,
+    world::paging::PagingOptions,
+    worlds::{check_name, DEFAULT_WORLD},
+    ServeOptions,
+};
+
+#[derive(Deserialize, Default, Debug)]
+pub struct Config {
+    pub bind: Option<String>,
+    pub assets: Option<PathBuf>,
+    pub log_level: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub max_worlds: Option<usize>,
+    /// the default world
+    #[serde(flatten)]
+    pub world: WorldConfig,
+    #[serde(default)]
+    pub worlds: BTreeMap<String, WorldConfig>,
+    /// settings that aren't recognised, which `load` rejects; flattening `world` loses its
+    /// `deny_unknown_fields`, so they end up here instead
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+/// The settings of one world
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WorldConfig {
+    pub world: Option<PathBuf>,
+    pub tick: Option<u64>,
+    pub journal: Option<PathBuf>,
+    #[serde(default)]
+    pub autosave: AutosaveConfig,
+    #[serde(default)]
+    pub paging: PagingConfig,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AutosaveConfig {
+    pub dir: Option<PathBuf>,
+    pub interval: Option<u64>,
+    pub keep: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PagingConfig {
+    pub dir: Option<PathBuf>,
+    pub memory_budget: Option<usize>,
+    pub cold_after: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: Option<PathBuf>,
+    pub interval: Option<u64>,
+}
+
+const LOG_LEVELS: &str = "off, error, warn, info, debug or trace";
+
+fn resolve(dir: &Path, path: &mut Option<PathBuf>) {
+    if let Some(path) = path {
+        *path = dir.join(&*path);
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .context(format!("Failed to read config {}", path.display()))?;
+        let mut config: Config =
+            toml::from_str(&text).context(format!("Failed to parse config {}", path.display()))?;
+        if let Some(key) = config.unknown.keys().next() {
+            bail!(
+                "Failed to parse config {}: unknown setting `{}'",
+                path.display(),
+                key
+            );
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        resolve(dir, &mut config.assets);
+        resolve(dir, &mut config.data_dir);
+        config.world.resolve(dir);
+        for world in config.worlds.values_mut() {
+            world.resolve(dir);
+        }
+        Ok(config)
+    }
+
+    /// Fill in whatever isn't set here from `other`
+    pub fn or(self, other: Config) -> Config {
+        let mut worlds = other.worlds;
+        worlds.extend(self.worlds);
+        Config {
+            bind: self.bind.or(other.bind),
+            assets: self.assets.or(other.assets),
+            log_level: self.log_level.or(other.log_level),
+            data_dir: self.data_dir.or(other.data_dir),
+            max_worlds: self.max_worlds.or(other.max_worlds),
+            world: self.world.or(other.world),
+            worlds,
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter> {
+        match &self.log_level {
+            None => Ok(LevelFilter::Info),
+            Some(level) => level.parse().map_err(|_| {
+                anyhow!(
+                    "log_level: `{}' is not a log level, expected {}",
+                    level,
+                    LOG_LEVELS
+                )
+            }),
+        }
+    }
+
+    /// Check the settings, reporting every problem at once, and fill in defaults
+    pub fn serve_options(self) -> Result<ServeOptions> {
+        let mut problems = Vec::new();
+        let bind = self.bind.as_deref().unwrap_or("0.0.0.0:3000");
+        let bind = bind.parse::<SocketAddr>().unwrap_or_else(|_| {
+            problems.push(format!(
+                "bind: `{}' is not an address and port, like 0.0.0.0:3000",
+                bind
+            ));
+            ([0, 0, 0, 0], 0).into()
+        });
+        if let Err(e) = self.log_level() {
+            problems.push(e.to_string());
+        }
+        let assets = self.assets.unwrap_or_else(|| PathBuf::from("assets"));
+        if !assets.is_dir() {
+            problems.push(format!("assets: {} is not a directory", assets.display()));
+        }
+        if self.max_worlds == Some(0) {
+            problems.push("max_worlds must be at least 1".to_owned());
+        }
+        self.world.check("", &mut problems);
+        for (name, world) in &self.worlds {
+            let prefix = format!("worlds.{}.", name);
+            if name == DEFAULT_WORLD {
+                problems.push(format!(
+                    "worlds.{}: the top level settings are for the default world",
+                    name
+                ));
+            } else if let Err(e) = check_name(name) {
+                problems.push(format!("worlds.{}: {}", name, e));
+            }
+            world.check(&prefix, &mut problems);
+        }
+        if !problems.is_empty() {
+            bail!("Invalid configuration:\n  {}", problems.join("\n  "));
+        }
+
+        let mut default = self.world;
+        default.world = default.world.or_else(|| Some(PathBuf::from("./primes.wi")));
+        let mut worlds = vec![(DEFAULT_WORLD.to_owned(), default.build())];
+        for (name, world) in self.worlds {
+            worlds.push((name, world.build()));
+        }
+        Ok(ServeOptions {
+            bind,
+            assets,
+            data_dir: self.data_dir,
+            max_worlds: self.max_worlds.unwrap_or(64),
+            worlds,
+        })
+    }
+}
+
+impl WorldConfig {
+    /// Load settings kept on their own, as for a world created while running
+    pub fn load(path: &Path) -> Result<WorldConfig> {
+        let text =
+            std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+        let mut config: WorldConfig =
+            toml::from_str(&text).context(format!("Failed to parse {}", path.display()))?;
+        config.resolve(path.parent().unwrap_or(Path::new(".")));
+        Ok(config)
+    }
+
+    fn resolve(&mut self, dir: &Path) {
+        resolve(dir, &mut self.world);
+        resolve(dir, &mut self.journal);
+        resolve(dir, &mut self.autosave.dir);
+        resolve(dir, &mut self.paging.dir);
+        resolve(dir, &mut self.database.path);
+    }
+
+    pub fn or(self, other: WorldConfig) -> WorldConfig {
+        WorldConfig {
+            world: self.world.or(other.world),
+            tick: self.tick.or(other.tick),
+            journal: self.journal.or(other.journal),
+            autosave: AutosaveConfig {
+                dir: self.autosave.dir.or(other.autosave.dir),
+                interval: self.autosave.interval.or(other.autosave.interval),
+                keep: self.autosave.keep.or(other.autosave.keep),
+            },
+            paging: PagingConfig {
+                dir: self.paging.dir.or(other.paging.dir),
+                memory_budget: self.paging.memory_budget.or(other.paging.memory_budget),
+                cold_after: self.paging.cold_after.or(other.paging.cold_after),
+            },
+            database: DatabaseConfig {
+                path: self.database.path.or(other.database.path),
+                interval: self.database.interval.or(other.database.interval),
+            },
+        }
+    }
+
+    // add whatever is wrong with the settings to `problems`, naming them after `prefix`
+    fn check(&self, prefix: &str, problems: &mut Vec<String>) {
+        let mut positive = |name: &str, value: Option<u64>| {
+            if value == Some(0) {
+                problems.push(format!("{}{} must be at least 1", prefix, name));
+            }
+        };
+        positive("tick", self.tick);
+        positive("autosave.interval", self.autosave.interval);
+        positive("autosave.keep", self.autosave.keep.map(|k| k as u64));
+        positive("database.interval", self.database.interval);
+        if self.autosave.dir.is_none()
+            && (self.autosave.interval.is_some() || self.autosave.keep.is_some())
+        {
+            problems.push(format!(
+                "{}autosave settings are given but not {}autosave.dir",
+                prefix, prefix
+            ));
+        }
+        if self.database.path.is_none() && self.database.interval.is_some() {
+            problems.push(format!(
+                "{}database.interval is given but not {}database.path",
+                prefix, prefix
+            ));
+        }
+        if self.paging.dir.is_some() && self.database.path.is_some() {
+            problems.push(format!(
+                "{}paging.dir and {}database.path can't both be given, the database is paged out to",
+                prefix, prefix
+            ));
+        }
+        if self.paging.dir.is_none()
+            && self.database.path.is_none()
+            && (self.paging.memory_budget.is_some() || self.paging.cold_after.is_some())
+        {
+            problems.push(format!(
+                "{}paging settings are given but neither {}paging.dir nor {}database.path",
+                prefix, prefix, prefix
+            ));
+        }
+    }
+
+    /// Check the settings and fill in defaults
+    pub fn options(self) -> Result<WorldOptions> {
+        let mut problems = Vec::new();
+        self.check("", &mut problems);
+        if !problems.is_empty() {
+            bail!("Invalid world settings:\n  {}", problems.join("\n  "));
+        }
+        Ok(self.build())
+    }
+
+    fn build(self) -> WorldOptions {
+        let defaults = WorldOptions::default();
+        WorldOptions {
+            world: self.world,
+            tick: self.tick.map_or(defaults.tick, Duration::from_millis),
+            autosave: self.autosave.dir.map(|dir| AutosaveOptions {
+                dir,
+                interval: Duration::from_secs(self.autosave.interval.unwrap_or(60)),
+                keep: self.autosave.keep.unwrap_or(5),
+            }),
+            journal: self.journal,
+            page_dir: self.paging.dir,
+            database: self.database.path,
+            database_interval: self
+                .database
+                .interval
+                .map_or(defaults.database_interval, Duration::from_secs),
+            paging: PagingOptions {
+                budget: self.paging.memory_budget.unwrap_or(defaults.paging.budget),
+                cold_after: self.paging.cold_after.unwrap_or(defaults.paging.cold_after),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    const FILE: &str = r#"
+        bind = "127.0.0.1:4000"
+        assets = "assets"
+        data_dir = "worlds"
+        world = "primes.wi"
+        tick = 50
+        journal = "world.journal"
+
+        [autosave]
+        dir = "saves"
+        keep = 3
+
+        [worlds.demo]
+        world = "demo.toml"
+
+        [worlds.demo.database]
+        path = "demo.db"
+    "#;
+
+    fn load(text: &str) -> Result<Config> {
+        let dir = temp_dir(&[("server.toml", text)]);
+        Config::load(&dir.path().join("server.toml"))
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_file() {
+        let dir = temp_dir(&[("server.toml", FILE)]);
+        let config = Config::load(&dir.path().join("server.toml")).unwrap();
+        let path = |name: &str| Some(dir.path().join(name));
+        assert_eq!(config.assets, path("assets"));
+        assert_eq!(config.data_dir, path("worlds"));
+        assert_eq!(config.world.world, path("primes.wi"));
+        assert_eq!(config.world.journal, path("world.journal"));
+        assert_eq!(config.world.autosave.dir, path("saves"));
+        let demo = &config.worlds["demo"];
+        assert_eq!(demo.world, path("demo.toml"));
+        assert_eq!(demo.database.path, path("demo.db"));
+        // settings that aren't paths are left alone
+        assert_eq!(config.bind.as_deref(), Some("127.0.0.1:4000"));
+        assert_eq!(config.world.tick, Some(50));
+    }
+
+    #[test]
+    fn unknown_settings() {
+        let err = load("tick = 5\ncolour = \"blue\"\n").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unknown setting `colour'"),
+            "{:#}",
+            err
+        );
+        for text in [
+            "[autosave]\ndir = \"saves\"\nevery = 5\n",
+            "[worlds.demo]\ntick = 5\nspeed = 2\n",
+            "[worlds.demo.paging]\nbudget = 5\n",
+        ] {
+            let err = load(text).unwrap_err();
+            assert!(format!("{:#}", err).contains("unknown field"), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn command_line_comes_first() {
+        let file = load(FILE).unwrap();
+        let args = Config {
+            bind: Some("0.0.0.0:5000".to_owned()),
+            world: WorldConfig {
+                tick: Some(20),
+                autosave: AutosaveConfig {
+                    interval: Some(30),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = args.or(file);
+        assert_eq!(config.bind.as_deref(), Some("0.0.0.0:5000"));
+        assert_eq!(config.world.tick, Some(20));
+        assert_eq!(config.world.autosave.interval, Some(30));
+        // the rest comes from the file
+        assert_eq!(config.world.autosave.keep, Some(3));
+        assert!(config.world.autosave.dir.is_some());
+        assert!(config.world.journal.is_some());
+        assert!(config.worlds.contains_key("demo"));
+    }
+
+    #[test]
+    fn defaults() {
+        let dir = temp_dir(&[]);
+        let config = Config {
+            assets: Some(dir.path().to_owned()),
+            ..Default::default()
+        };
+        let opts = config.serve_options().unwrap();
+        assert_eq!(opts.bind, "0.0.0.0:3000".parse().unwrap());
+        assert_eq!(opts.max_worlds, 64);
+        let [(name, world)] = &opts.worlds[..] else {
+            panic!("{} worlds", opts.worlds.len());
+        };
+        assert_eq!(name, DEFAULT_WORLD);
+        assert_eq!(world.world, Some(PathBuf::from("./primes.wi")));
+        assert!(world.autosave.is_none());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let text = r#"
+            bind = "nowhere"
+            log_level = "loud"
+            assets = "missing"
+            tick = 0
+
+            [autosave]
+            keep = 0
+
+            [database]
+            interval = 5
+
+            [worlds.default]
+            tick = 10
+
+            [worlds."bad name"]
+            tick = 10
+
+            [worlds.demo.paging]
+            dir = "pages"
+            [worlds.demo.database]
+            path = "demo.db"
+
+            [worlds.other.paging]
+            cold_after = 5
+        "#;
+        let Err(err) = load(text).unwrap().serve_options() else {
+            panic!("accepted");
+        };
+        let err = err.to_string();
+        for problem in [
+            "bind: `nowhere' is not an address",
+            "log_level: `loud' is not a log level",
+            "missing is not a directory",
+            "\n  tick must be at least 1",
+            "\n  autosave.keep must be at least 1",
+            "\n  autosave settings are given but not autosave.dir",
+            "\n  database.interval is given but not database.path",
+            "worlds.default: the top level settings are for the default world",
+            "worlds.bad name: World names may only contain",
+            "worlds.demo.paging.dir and worlds.demo.database.path can't both be given",
+            "worlds.other.paging settings are given but neither",
+        ] {
+            assert!(err.contains(problem), "{:?} in {}", problem, err);
+        }
+        assert_eq!(err.lines().count(), 12, "{}", err);
+
+        let err = WorldConfig {
+            tick: Some(0),
+            ..Default::default()
+        }
+        .options()
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid world settings:\n  tick must be at least 1"
+        );
+    }
+}
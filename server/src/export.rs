@@ -0,0 +1,515 @@
+use std::{io::Write, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use wire_universe::{pack::MAX_CELLS, CellState};
+
+use crate::world::World;
+
+/// The colors used by the web client, so exported images look like the canvas
+pub fn cell_color(c: CellState) -> [u8; 3] {
+    match c {
+        CellState::Alive => [0, 0, 255],
+        CellState::Dead => [128, 128, 128],
+        CellState::Empty => [255, 255, 255],
+        CellState::Wire => [255, 165, 0],
+    }
+}
+
+// indexed by `CellState::to_bits`
+fn palette() -> Vec<u8> {
+    [
+        CellState::Empty,
+        CellState::Wire,
+        CellState::Alive,
+        CellState::Dead,
+    ]
+    .into_iter()
+    .flat_map(cell_color)
+    .collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationOptions {
+    /// number of frames to render, the first being the world as given
+    pub generations: u32,
+    pub frame_delay: Duration,
+    /// side length of a cell in pixels
+    pub scale: u32,
+}
+
+impl Default for AnimationOptions {
+    fn default() -> Self {
+        AnimationOptions {
+            generations: 50,
+            frame_delay: Duration::from_millis(100),
+            scale: 4,
+        }
+    }
+}
+
+// largest frame, in pixels, that an animation will be rendered at
+const MAX_PIXELS: u64 = 1 << 26;
+
+// render the slice at `x, y, w, h` into an indexed image buffer
+fn render_frame(world: &World, x: i32, y: i32, w: i32, h: i32, scale: u32, buf: &mut Vec<u8>) {
+    buf.clear();
+    for row in world.copy_slice(x, y, w, h) {
+        let line: Vec<u8> = row
+            .iter()
+            .flat_map(|&c| std::iter::repeat_n(c.to_bits(), scale as usize))
+            .collect();
+        for _ in 0..scale {
+            buf.extend_from_slice(&line);
+        }
+    }
+}
+
+/// Render the rectangle `x, y, w, h` of `world` as it evolves, one frame per generation.
+/// `world` itself is left untouched.
+#[allow(clippy::too_many_arguments)]
+pub fn render_animation<W: Write>(
+    world: &World,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+    format: AnimationFormat,
+    opts: &AnimationOptions,
+    mut out: W,
+) -> Result<()> {
+    if w <= 0 || h <= 0 {
+        bail!("Region must have a positive size, got {}x{}", w, h);
+    }
+    if opts.scale == 0 || opts.generations == 0 {
+        bail!("Scale and generation count must be at least 1");
+    }
+    let too_big = || {
+        anyhow!(
+            "Image of {}x{} cells at scale {} is too large",
+            w,
+            h,
+            opts.scale
+        )
+    };
+    if w as u64 * h as u64 > MAX_CELLS as u64 {
+        bail!(
+            "Region of {}x{} cells is too large, the limit is {} cells",
+            w,
+            h,
+            MAX_CELLS
+        );
+    }
+    let width = (w as u32).checked_mul(opts.scale).ok_or_else(too_big)?;
+    let height = (h as u32).checked_mul(opts.scale).ok_or_else(too_big)?;
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err(too_big());
+    }
+
+    let mut world = world.clone();
+    let mut buf = Vec::new();
+    match format {
+        AnimationFormat::Gif => {
+            let width = u16::try_from(width).map_err(|_| too_big())?;
+            let height = u16::try_from(height).map_err(|_| too_big())?;
+            // gif delays are in hundredths of a second
+            let delay = u16::try_from(opts.frame_delay.as_millis() / 10)
+                .context("Frame delay too long for gif")?;
+            let mut encoder = gif::Encoder::new(&mut out, width, height, &palette())?;
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+            for _ in 0..opts.generations {
+                render_frame(&world, x, y, w, h, opts.scale, &mut buf);
+                let frame = gif::Frame {
+                    width,
+                    height,
+                    delay,
+                    buffer: buf.as_slice().into(),
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&frame)?;
+                world.step()?;
+            }
+            // writes the trailer, which dropping the encoder would do without reporting errors
+            encoder.into_inner()?;
+        }
+        AnimationFormat::Apng => {
+            let delay = u16::try_from(opts.frame_delay.as_millis())
+                .context("Frame delay too long for apng")?;
+            let mut encoder = png::Encoder::new(&mut out, width, height);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(palette());
+            encoder.set_animated(opts.generations, 0)?;
+            encoder.set_frame_delay(delay, 1000)?;
+            let mut writer = encoder.write_header()?;
+            for _ in 0..opts.generations {
+                render_frame(&world, x, y, w, h, opts.scale, &mut buf);
+                writer.write_image_data(&buf)?;
+                world.step()?;
+            }
+            writer.finish()?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct SvgOptions {
+    /// side length of a cell in svg user units
+    pub scale: u32,
+    /// draw lines between cells
+    pub grid: bool,
+    /// label world coordinates along the top and left edges
+    pub rulers: bool,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        SvgOptions {
+            scale: 10,
+            grid: false,
+            rulers: false,
+        }
+    }
+}
+
+// a block of identical cells, in cells relative to the exported region
+struct Block {
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+    cell: CellState,
+}
+
+// merge horizontal runs of identical cells, then stack runs with the same span and state
+// from consecutive rows into taller rectangles
+fn merge_blocks(tiles: &[Vec<CellState>]) -> Vec<Block> {
+    let mut done = Vec::new();
+    let mut open: Vec<Block> = Vec::new();
+    for (y, row) in tiles.iter().enumerate() {
+        let y = y as i32;
+        let mut runs = Vec::new();
+        let mut x = 0;
+        while x < row.len() {
+            let start = x;
+            while x < row.len() && row[x] == row[start] {
+                x += 1;
+            }
+            if row[start] != CellState::Empty {
+                runs.push((start as i32, (x - start) as i32, row[start]));
+            }
+        }
+        let mut next = Vec::new();
+        for (x, w, cell) in runs {
+            match open
+                .iter()
+                .position(|b| b.x == x && b.w == w && b.cell == cell)
+            {
+                Some(i) => {
+                    let mut b = open.swap_remove(i);
+                    b.h += 1;
+                    next.push(b);
+                }
+                None => next.push(Block {
+                    x,
+                    y,
+                    w,
+                    h: 1,
+                    cell,
+                }),
+            }
+        }
+        done.append(&mut open);
+        open = next;
+    }
+    done.append(&mut open);
+    done
+}
+
+fn hex_color(c: CellState) -> String {
+    let [r, g, b] = cell_color(c);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Render the rectangle `x, y, w, h` of `world` as an svg image
+pub fn render_svg<W: Write>(
+    world: &World,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+    opts: &SvgOptions,
+    mut out: W,
+) -> Result<()> {
+    if w <= 0 || h <= 0 {
+        bail!("Region must have a positive size, got {}x{}", w, h);
+    }
+    if opts.scale == 0 {
+        bail!("Scale must be at least 1");
+    }
+    let s = opts.scale as i64;
+    let margin = if opts.rulers { 30 } else { 0 };
+    let (pw, ph) = (w as i64 * s, h as i64 * s);
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+        pw + margin,
+        ph + margin
+    )?;
+    writeln!(
+        out,
+        r#"<g transform="translate({margin} {margin})" shape-rendering="crispEdges">"#
+    )?;
+    writeln!(
+        out,
+        r#"<rect width="{}" height="{}" fill="{}"/>"#,
+        pw,
+        ph,
+        hex_color(CellState::Empty)
+    )?;
+    let blocks = merge_blocks(&world.copy_slice(x, y, w, h));
+    for cell in [CellState::Wire, CellState::Alive, CellState::Dead] {
+        writeln!(out, r#"<g fill="{}">"#, hex_color(cell))?;
+        for b in blocks.iter().filter(|b| b.cell == cell) {
+            writeln!(
+                out,
+                r#"<rect x="{}" y="{}" width="{}" height="{}"/>"#,
+                b.x as i64 * s,
+                b.y as i64 * s,
+                b.w as i64 * s,
+                b.h as i64 * s
+            )?;
+        }
+        writeln!(out, "</g>")?;
+    }
+    if opts.grid {
+        let mut path = String::new();
+        for i in 0..=w as i64 {
+            path += &format!("M{} 0V{}", i * s, ph);
+        }
+        for j in 0..=h as i64 {
+            path += &format!("M0 {}H{}", j * s, pw);
+        }
+        writeln!(
+            out,
+            r##"<path d="{}" stroke="#c0c0c0" stroke-width="{}" fill="none"/>"##,
+            path,
+            s as f64 / 20.
+        )?;
+    }
+    writeln!(out, "</g>")?;
+    if opts.rulers {
+        // label every `step` cells, keeping labels at least 40 units apart
+        let step = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000]
+            .into_iter()
+            .find(|&step| step * s >= 40)
+            .unwrap_or(1000);
+        writeln!(
+            out,
+            r#"<g font-family="monospace" font-size="10" fill="black" stroke="black">"#
+        )?;
+        for i in (0..w as i64).filter(|i| (x as i64 + i).rem_euclid(step) == 0) {
+            let px = margin + i * s;
+            writeln!(
+                out,
+                r#"<line x1="{px}" y1="{}" x2="{px}" y2="{margin}"/><text x="{}" y="{}" stroke="none">{}</text>"#,
+                margin - 6,
+                px + 2,
+                margin - 8,
+                x as i64 + i
+            )?;
+        }
+        for j in (0..h as i64).filter(|j| (y as i64 + j).rem_euclid(step) == 0) {
+            let py = margin + j * s;
+            writeln!(
+                out,
+                r#"<line x1="{}" y1="{py}" x2="{margin}" y2="{py}"/><text x="{}" y="{}" stroke="none" text-anchor="end">{}</text>"#,
+                margin - 6,
+                margin - 8,
+                py + 10,
+                y as i64 + j
+            )?;
+        }
+        writeln!(out, "</g>")?;
+    }
+    writeln!(out, "</svg>")?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wire_universe::Point;
+
+    // an electron moving right along a short wire
+    fn electron() -> World {
+        let mut world = World::new();
+        let row = [
+            CellState::Dead,
+            CellState::Alive,
+            CellState::Wire,
+            CellState::Wire,
+        ];
+        for (x, cell) in row.into_iter().enumerate() {
+            world.set_tile(Point { x: x as i32, y: 0 }, cell).unwrap();
+        }
+        world
+    }
+
+    const OPTS: AnimationOptions = AnimationOptions {
+        generations: 3,
+        frame_delay: Duration::from_millis(100),
+        scale: 2,
+    };
+
+    // the indexed pixels each frame of `electron` should have at `OPTS.scale`
+    fn expected() -> Vec<Vec<u8>> {
+        use CellState::*;
+        [
+            [Dead, Alive, Wire, Wire],
+            [Wire, Dead, Alive, Wire],
+            [Wire, Wire, Dead, Alive],
+        ]
+        .iter()
+        .map(|row| {
+            let line: Vec<u8> = row.iter().flat_map(|c| [c.to_bits(); 2]).collect();
+            line.repeat(2)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn gif_frames() {
+        let mut data = Vec::new();
+        render_animation(
+            &electron(),
+            0,
+            0,
+            4,
+            1,
+            AnimationFormat::Gif,
+            &OPTS,
+            &mut data,
+        )
+        .unwrap();
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(data.as_slice())
+            .unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (8, 2));
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.delay, 10);
+            frames.push(frame.buffer.to_vec());
+        }
+        assert_eq!(frames, expected());
+    }
+
+    #[test]
+    fn apng_frames() {
+        let mut data = Vec::new();
+        render_animation(
+            &electron(),
+            0,
+            0,
+            4,
+            1,
+            AnimationFormat::Apng,
+            &OPTS,
+            &mut data,
+        )
+        .unwrap();
+        let mut decoder = png::Decoder::new(data.as_slice());
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let mut reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (8, 2));
+        let frames = info.animation_control.unwrap().num_frames;
+        assert_eq!(frames, 3);
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frames: Vec<Vec<u8>> = (0..frames)
+            .map(|_| {
+                let out = reader.next_frame(&mut buf).unwrap();
+                buf[..out.buffer_size()].to_vec()
+            })
+            .collect();
+        assert_eq!(frames, expected());
+    }
+
+    #[test]
+    fn svg_blocks() {
+        use CellState::*;
+        let mut world = World::new();
+        // a 3x2 block of wire, a wire run of a different width below it, and a lone head
+        for (x, y, cell) in [
+            (0, 0, Wire),
+            (1, 0, Wire),
+            (2, 0, Wire),
+            (0, 1, Wire),
+            (1, 1, Wire),
+            (2, 1, Wire),
+            (0, 2, Wire),
+            (1, 2, Wire),
+            (4, 1, Alive),
+        ] {
+            world
+                .set_tile(
+                    Point {
+                        x: x + 10,
+                        y: y + 20,
+                    },
+                    cell,
+                )
+                .unwrap();
+        }
+        let mut data = Vec::new();
+        render_svg(&world, 10, 20, 5, 3, &SvgOptions::default(), &mut data).unwrap();
+        let svg = String::from_utf8(data).unwrap();
+        let rects: Vec<&str> = svg.lines().filter(|l| l.starts_with("<rect x=")).collect();
+        assert_eq!(
+            rects,
+            [
+                r#"<rect x="0" y="0" width="30" height="20"/>"#,
+                r#"<rect x="0" y="20" width="20" height="10"/>"#,
+                r#"<rect x="40" y="10" width="10" height="10"/>"#,
+            ]
+        );
+        assert!(svg.contains(r##"<rect width="50" height="30" fill="#ffffff"/>"##));
+        assert!(svg.ends_with("</svg>\n"));
+    }
+
+    #[test]
+    fn oversized_animation() {
+        let err = render_animation(
+            &World::new(),
+            0,
+            0,
+            1 << 13,
+            1 << 13,
+            AnimationFormat::Apng,
+            &OPTS,
+            std::io::sink(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+        let err = render_animation(
+            &World::new(),
+            0,
+            0,
+            1 << 12,
+            1 << 12,
+            AnimationFormat::Apng,
+            &AnimationOptions { scale: 4, ..OPTS },
+            std::io::sink(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+    }
+}
@@ -0,0 +1,551 @@
+//! Running a single world: its tick task, channels and persistence.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::{debug, error, info, warn};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot, watch},
+    task::{self, JoinHandle},
+    time::interval,
+};
+use wire_universe::{CellState, Point};
+
+use crate::{
+    autosave::{self, AutosaveOptions},
+    interest::Interest,
+    journal::{self, Entry, Journal, JournalReader},
+    store::SqliteStore,
+    world::{
+        paging::{DirStore, PagingOptions},
+        World,
+    },
+};
+
+#[derive(Clone, Debug)]
+pub struct WorldOptions {
+    /// the world to start with when there is nothing to resume from; empty if not given
+    pub world: Option<PathBuf>,
+    /// time between generations
+    pub tick: Duration,
+    pub autosave: Option<AutosaveOptions>,
+    /// where to append every edit, replayed on startup to recover edits made after the
+    /// last autosave
+    pub journal: Option<PathBuf>,
+    /// directory to page cold chunks out to, for worlds too big to keep in memory
+    pub page_dir: Option<PathBuf>,
+    /// database to keep the world in, resumed from if it has one, and paged out to
+    pub database: Option<PathBuf>,
+    /// time between writing changed chunks to the database
+    pub database_interval: Duration,
+    pub paging: PagingOptions,
+}
+
+impl Default for WorldOptions {
+    fn default() -> Self {
+        WorldOptions {
+            world: None,
+            tick: Duration::from_millis(100),
+            autosave: None,
+            journal: None,
+            page_dir: None,
+            database: None,
+            database_interval: Duration::from_secs(10),
+            paging: PagingOptions {
+                budget: 10_000_000,
+                cold_after: 600,
+            },
+        }
+    }
+}
+
+pub(crate) struct CellModification {
+    pub x: i32,
+    pub y: i32,
+    pub cell: CellState,
+}
+
+/// How many generations ahead of the world edits can be scheduled
+pub(crate) const MAX_AHEAD: u64 = 10_000;
+
+// why scheduled edits weren't made
+pub(crate) enum Unmade {
+    // the world was already at this generation, past the one asked for
+    TooLate(u64),
+    // the world was at this generation, more than `MAX_AHEAD` before the one asked for
+    TooFarAhead(u64),
+    // the world stopped first
+    Stopped,
+}
+
+// whether scheduled edits were made, with the id of the request asking for them, the
+// generation they were for and how many cells they set
+pub(crate) struct Made {
+    pub request_id: Option<u32>,
+    pub generation: u64,
+    pub cells: usize,
+    pub result: Result<(), Unmade>,
+}
+
+// edits to make to one generation, before it's stepped. If they're dropped without being
+// answered, as they are when the world stops, they're answered as `Unmade::Stopped`.
+pub(crate) struct Scheduled {
+    generation: u64,
+    modifications: Vec<CellModification>,
+    request_id: Option<u32>,
+    cells: usize,
+    done: mpsc::UnboundedSender<Made>,
+    answered: bool,
+}
+
+impl Scheduled {
+    pub fn new(
+        generation: u64,
+        modifications: Vec<CellModification>,
+        request_id: Option<u32>,
+        done: mpsc::UnboundedSender<Made>,
+    ) -> Scheduled {
+        Scheduled {
+            generation,
+            cells: modifications.len(),
+            modifications,
+            request_id,
+            done,
+            answered: false,
+        }
+    }
+
+    fn answer(&mut self, result: Result<(), Unmade>) {
+        self.answered = true;
+        _ = self.done.send(Made {
+            request_id: self.request_id,
+            generation: self.generation,
+            cells: self.cells,
+            result,
+        });
+    }
+}
+
+impl Drop for Scheduled {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.answer(Err(Unmade::Stopped));
+        }
+    }
+}
+
+// what connections ask of `world_updator`
+pub(crate) enum WorldCommand {
+    Modify(CellModification),
+    // edits made all in the same generation
+    ModifyMany(Vec<CellModification>),
+    // edits made all in the generation asked for, unless it's already been stepped past
+    ModifyAt(Scheduled),
+    // a client is looking at this rectangle, so it should be kept in memory
+    View { x: i32, y: i32, w: i32, h: i32 },
+}
+
+/// A running world
+pub struct WorldHandle {
+    pub name: String,
+    pub opts: WorldOptions,
+    /// each generation, published once and shared by everyone looking at it
+    pub(crate) world_sender: watch::Sender<Arc<World>>,
+    pub(crate) update_sender: mpsc::UnboundedSender<WorldCommand>,
+    pub(crate) interest: Arc<Interest>,
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl WorldHandle {
+    /// Load or resume the world and start ticking it. `seed` stands in for the world file.
+    pub fn start(name: &str, opts: WorldOptions, seed: Option<World>) -> Result<WorldHandle> {
+        let (world, persistence) = task::block_in_place(|| load(&opts, seed))?;
+        let (world_sender, _) = watch::channel(Arc::new(world.clone()));
+        let (update_sender, update_receiver) = mpsc::unbounded_channel();
+        let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+        let interest = Arc::new(Interest::new());
+        let task = task::spawn(world_updator(
+            world,
+            world_sender.clone(),
+            interest.clone(),
+            update_receiver,
+            persistence,
+            shutdown_receiver,
+            opts.tick,
+        ));
+        Ok(WorldHandle {
+            name: name.to_owned(),
+            opts,
+            world_sender,
+            update_sender,
+            interest,
+            shutdown: Mutex::new(Some(shutdown_sender)),
+            task: Mutex::new(Some(task)),
+        })
+    }
+
+    /// The world as of the latest generation
+    pub fn snapshot(&self) -> Arc<World> {
+        self.world_sender.borrow().clone()
+    }
+
+    /// How many clients are watching
+    pub fn clients(&self) -> usize {
+        self.world_sender.receiver_count()
+    }
+
+    /// Stop ticking, saving the world first
+    pub async fn stop(&self) {
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            _ = shutdown.send(());
+        }
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            if let Err(e) = task.await {
+                error!("World `{}' stopped abnormally: {}", self.name, e);
+            }
+        }
+    }
+}
+
+// resume or load the world, replay the journal and set up persistence
+fn load(opts: &WorldOptions, seed: Option<World>) -> Result<(World, Persistence)> {
+    if opts.page_dir.is_some() && opts.database.is_some() {
+        bail!("A database is paged out to itself, so it can't be used with a page directory");
+    }
+    let store = match &opts.database {
+        Some(path) => Some(Arc::new(SqliteStore::open(path)?)),
+        None => None,
+    };
+    let stored = match &store {
+        Some(store) => World::from_store(store.clone(), opts.paging.clone())?,
+        None => None,
+    };
+    let in_store = stored.is_some();
+    let resumed = match (&opts.autosave, in_store) {
+        (Some(autosave), false) => autosave::load_latest(autosave)?,
+        _ => None,
+    };
+    let mut world = match (stored, resumed) {
+        (Some(world), _) => {
+            info!(
+                "Resuming from the database at generation {}",
+                world.generation()
+            );
+            world
+        }
+        (None, Some((path, world))) => {
+            info!(
+                "Resuming from {} at generation {}",
+                path.display(),
+                world.generation()
+            );
+            world
+        }
+        (None, None) => match (seed, &opts.world) {
+            (Some(seed), _) => seed,
+            (None, Some(path)) => World::load(path)?,
+            (None, None) => World::new(),
+        },
+    };
+    let journal = match &opts.journal {
+        Some(path) => {
+            if path.exists() {
+                let mut replayed = world.clone();
+                match JournalReader::open(path)
+                    .and_then(|entries| journal::replay(&mut replayed, entries, None))
+                {
+                    Ok(report) => {
+                        info!(
+                            "Replayed {} edits from {} up to generation {}",
+                            report.edits,
+                            path.display(),
+                            replayed.generation()
+                        );
+                        world = replayed;
+                    }
+                    Err(e) => warn!("Not replaying journal: {:#}", e),
+                }
+            }
+            Some(Journal::open(path)?)
+        }
+        None => None,
+    };
+    if let Some(dir) = &opts.page_dir {
+        world.enable_paging(Arc::new(DirStore::open(dir)?), opts.paging.clone());
+    } else if let (Some(store), false) = (store, in_store) {
+        world.attach_store(store, opts.paging.clone())?;
+    }
+    let persistence = Persistence {
+        autosave: opts.autosave.clone(),
+        journal,
+        last_save: Instant::now(),
+        flush_interval: opts.database.as_ref().map(|_| opts.database_interval),
+        last_flush: Instant::now(),
+    };
+    Ok((world, persistence))
+}
+
+// autosaves, journaling and database writes for `world_updator`
+struct Persistence {
+    autosave: Option<AutosaveOptions>,
+    journal: Option<Journal>,
+    last_save: Instant,
+    flush_interval: Option<Duration>,
+    last_flush: Instant,
+}
+
+impl Persistence {
+    fn record(&mut self, entry: Entry) {
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.record(entry) {
+                error!("Failed to write journal: {:#}", e);
+            }
+        }
+    }
+
+    // called at the start of each generation, and with `force` on shutdown
+    fn checkpoint(&mut self, world: &mut World, force: bool) {
+        let due = self
+            .autosave
+            .as_ref()
+            .is_some_and(|opts| self.last_save.elapsed() >= opts.interval);
+        if due || force {
+            self.last_save = Instant::now();
+            if let Some(opts) = &self.autosave {
+                match task::block_in_place(|| autosave::save(opts, world)) {
+                    Ok(path) => {
+                        debug!("Saved {}", path.display());
+                        // the snapshot holds everything journaled so far
+                        if let Some(journal) = &mut self.journal {
+                            if let Err(e) = journal.clear() {
+                                error!("Failed to clear journal: {:#}", e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("{:#}", e),
+                }
+            }
+            self.record(Entry::Checkpoint {
+                generation: world.generation(),
+                hash: world.state_hash(),
+            });
+        }
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.flush() {
+                error!("Failed to write journal: {:#}", e);
+            }
+        }
+        let flush_due = self
+            .flush_interval
+            .is_some_and(|interval| self.last_flush.elapsed() >= interval);
+        if flush_due || (force && self.flush_interval.is_some()) {
+            self.last_flush = Instant::now();
+            match task::block_in_place(|| world.flush()) {
+                Ok(count) => debug!("Wrote {} chunks to the database", count),
+                Err(e) => error!("{:#}", e),
+            }
+        }
+    }
+}
+
+fn modify(world: &mut World, persistence: &mut Persistence, modification: CellModification) {
+    let CellModification { x, y, cell } = modification;
+    if let Err(e) = world.edit_tile(Point { x, y }, cell) {
+        error!("Failed to edit ({}, {}): {:#}", x, y, e);
+        return;
+    }
+    persistence.record(Entry::Edit {
+        generation: world.generation(),
+        pos: Point { x, y },
+        cell,
+    });
+}
+
+// make edits for the generation `world` is at, or hold on to them for a later one
+fn schedule(
+    world: &mut World,
+    persistence: &mut Persistence,
+    later: &mut BTreeMap<u64, Vec<Scheduled>>,
+    mut scheduled: Scheduled,
+) {
+    let generation = world.generation();
+    if scheduled.generation > generation.saturating_add(MAX_AHEAD) {
+        scheduled.answer(Err(Unmade::TooFarAhead(generation)));
+    } else if scheduled.generation > generation {
+        later
+            .entry(scheduled.generation)
+            .or_default()
+            .push(scheduled);
+    } else if scheduled.generation == generation {
+        for modification in std::mem::take(&mut scheduled.modifications) {
+            modify(world, persistence, modification);
+        }
+        scheduled.answer(Ok(()));
+    } else {
+        scheduled.answer(Err(Unmade::TooLate(generation)));
+    }
+}
+
+// generations between looking for chunks to page out
+const PAGE_OUT_EVERY: u64 = 64;
+
+async fn world_updator(
+    mut world: World,
+    world_sender: watch::Sender<Arc<World>>,
+    interest: Arc<Interest>,
+    mut update_receiver: mpsc::UnboundedReceiver<WorldCommand>,
+    mut persistence: Persistence,
+    mut shutdown: oneshot::Receiver<()>,
+    tick: Duration,
+) {
+    let mut interval = interval(tick);
+    // edits for generations still to come
+    let mut later = BTreeMap::new();
+    // whether the world has moved on to a generation it hasn't been shared at yet
+    let mut stepped = true;
+    loop {
+        if stepped {
+            // chunks still being watched mustn't go cold just because the view hasn't moved
+            if let Err(e) = task::block_in_place(|| world.touch_chunks(interest.watched())) {
+                error!("{:#}", e);
+            }
+            interest.record(&world);
+            // replacing rather than queueing, so however far behind connections are, only the
+            // generations they're still sending are kept around
+            world_sender.send_replace(Arc::new(world.clone()));
+            world.clear_edited();
+            if let Some(scheduled) = later.remove(&world.generation()) {
+                task::block_in_place(|| {
+                    for scheduled in scheduled {
+                        schedule(&mut world, &mut persistence, &mut later, scheduled);
+                    }
+                });
+            }
+        }
+        loop {
+            select! {
+                Some(command) = update_receiver.recv() => match command {
+                    // edits to paged out chunks read them back in first
+                    WorldCommand::Modify(modification) => task::block_in_place(|| {
+                        modify(&mut world, &mut persistence, modification);
+                    }),
+                    WorldCommand::ModifyMany(modifications) => task::block_in_place(|| {
+                        for modification in modifications {
+                            modify(&mut world, &mut persistence, modification);
+                        }
+                    }),
+                    WorldCommand::ModifyAt(scheduled) => task::block_in_place(|| {
+                        schedule(&mut world, &mut persistence, &mut later, scheduled);
+                    }),
+                    WorldCommand::View {x, y, w, h} => {
+                        if let Err(e) = task::block_in_place(|| world.touch(x, y, w, h)) {
+                            error!("{:#}", e);
+                        }
+                    }
+                },
+                _ = interval.tick() => {
+                    break;
+                }
+                _ = &mut shutdown => {
+                    persistence.checkpoint(&mut world, true);
+                    // edits still scheduled are answered as they're dropped
+                    return;
+                }
+            }
+        }
+        // stepping reads back chunks electrons are about to reach, and if one can't be read
+        // the generation is tried again next tick
+        if let Err(e) = task::block_in_place(|| world.step()) {
+            error!("Failed to step the world: {:#}", e);
+            stepped = false;
+            continue;
+        }
+        stepped = true;
+        persistence.checkpoint(&mut world, false);
+        if world.generation().is_multiple_of(PAGE_OUT_EVERY) {
+            match task::block_in_place(|| world.page_out_cold()) {
+                Ok(0) => {}
+                Ok(count) => debug!("Paged out {} chunks", count),
+                Err(e) => error!("{:#}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sorted_cells;
+
+    fn persistence() -> Persistence {
+        Persistence {
+            autosave: None,
+            journal: None,
+            last_save: Instant::now(),
+            flush_interval: None,
+            last_flush: Instant::now(),
+        }
+    }
+
+    fn wire_at(x: i32) -> Vec<CellModification> {
+        vec![CellModification {
+            x,
+            y: 0,
+            cell: CellState::Wire,
+        }]
+    }
+
+    #[test]
+    fn scheduling() {
+        let mut world = World::new();
+        let mut persistence = persistence();
+        let mut later = BTreeMap::new();
+        let (done, mut made) = mpsc::unbounded_channel();
+        let mut schedule_at = |world: &mut World, generation, x, id| {
+            let scheduled = Scheduled::new(generation, wire_at(x), Some(id), done.clone());
+            schedule(world, &mut persistence, &mut later, scheduled);
+        };
+        schedule_at(&mut world, 0, 1, 1);
+        schedule_at(&mut world, 3, 2, 2);
+        schedule_at(&mut world, MAX_AHEAD + 1, 3, 3);
+        world.set_generation(5);
+        schedule_at(&mut world, 4, 4, 4);
+        drop(later);
+
+        let mut results = Vec::new();
+        while let Ok(made) = made.try_recv() {
+            let result = match made.result {
+                Ok(()) => "made".to_owned(),
+                Err(Unmade::TooLate(now)) => format!("too late at {}", now),
+                Err(Unmade::TooFarAhead(now)) => format!("too far ahead of {}", now),
+                Err(Unmade::Stopped) => "stopped".to_owned(),
+            };
+            results.push((
+                made.request_id.unwrap(),
+                made.generation,
+                made.cells,
+                result,
+            ));
+        }
+        results.sort_by_key(|r| r.0);
+        let expected = [
+            (1, 0, 1, "made"),
+            (2, 3, 1, "stopped"),
+            (3, MAX_AHEAD + 1, 1, "too far ahead of 0"),
+            (4, 4, 1, "too late at 5"),
+        ];
+        assert_eq!(results.len(), expected.len());
+        for (result, expected) in results.iter().zip(expected) {
+            assert_eq!((result.0, result.1, result.2, result.3.as_str()), expected);
+        }
+        assert_eq!(sorted_cells(&world), [(1, 0, CellState::Wire)]);
+    }
+}
@@ -0,0 +1,636 @@
+//! Sharing the work of keeping connections up to date.
+//!
+//! Views are grown to whole chunks. Each generation, a connection is sent the outermost ring
+//! of each chunk it's looking at if the ring changed, or the whole chunk if it was edited,
+//! followed by a `Tick`. The client works out the rest itself, since the inside of a chunk
+//! only depends on the chunk as it was. What to send for a chunk is worked out and encoded
+//! once per generation and `Format`, however many connections are looking at it. Moving the
+//! view only sends the chunks newly in view.
+//!
+//! The last `HISTORY` generations of each ring are recorded as the world is stepped, so a
+//! connection a few generations behind can be sent them all at once to replay.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use wire_universe::{
+    proto::{checksum, Encoding, FromServer, Rect, Tiles, CHECKSUM_EVERY, CHUNK_SIZE},
+    CellState, Point,
+};
+
+use crate::world::{
+    paging::{chunk_key, chunk_origin, ChunkKey},
+    World,
+};
+
+/// The most chunks across or down a view can be
+pub const MAX_VIEW_CHUNKS: i32 = 64;
+
+/// How messages are sent to a client
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum Format {
+    /// in binary frames
+    #[default]
+    MessagePack,
+    /// in text frames
+    Json,
+}
+
+/// An encoded `FromServer` message
+pub type Encoded = Arc<[u8]>;
+
+pub fn encode(msg: &FromServer, format: Format) -> Encoded {
+    match format {
+        Format::MessagePack => rmp_serde::to_vec(msg).unwrap().into(),
+        Format::Json => serde_json::to_vec(msg).unwrap().into(),
+    }
+}
+
+// the last chunk across or down whose end, one past its last cell, is still in range
+const LAST_CHUNK: i32 = i32::MAX / CHUNK_SIZE - 1;
+
+/// `view` grown to whole chunks, and shrunk to at most `MAX_VIEW_CHUNKS` each way and to
+/// chunks that end before the edge of the coordinate range
+pub fn chunk_view(view: Rect) -> Rect {
+    if view.w <= 0 || view.h <= 0 {
+        return Rect { w: 0, h: 0, ..view };
+    }
+    let (cx0, cy0) = chunk_key(Point {
+        x: view.x,
+        y: view.y,
+    });
+    let (cx1, cy1) = chunk_key(Point {
+        x: view.x.saturating_add(view.w - 1),
+        y: view.y.saturating_add(view.h - 1),
+    });
+    let (cx0, cy0) = (cx0.min(LAST_CHUNK), cy0.min(LAST_CHUNK));
+    let (cx1, cy1) = (cx1.min(LAST_CHUNK), cy1.min(LAST_CHUNK));
+    let origin = chunk_origin((cx0, cy0));
+    Rect {
+        x: origin.x,
+        y: origin.y,
+        w: (cx1 - cx0 + 1).min(MAX_VIEW_CHUNKS) * CHUNK_SIZE,
+        h: (cy1 - cy0 + 1).min(MAX_VIEW_CHUNKS) * CHUNK_SIZE,
+    }
+}
+
+// the chunks making up a view from `chunk_view`
+fn view_chunks(view: Rect) -> Vec<ChunkKey> {
+    let (cx0, cy0) = chunk_key(Point {
+        x: view.x,
+        y: view.y,
+    });
+    let mut keys = Vec::new();
+    for cy in cy0..cy0 + view.h / CHUNK_SIZE {
+        for cx in cx0..cx0 + view.w / CHUNK_SIZE {
+            keys.push((cx, cy));
+        }
+    }
+    keys
+}
+
+/// How many generations of each chunk's ring are kept, for connections that fell behind
+pub const HISTORY: usize = 16;
+
+/// The chunks a world's connections are looking at
+#[derive(Default)]
+pub struct Interest {
+    chunks: Mutex<HashMap<ChunkKey, Watched>>,
+}
+
+struct Watched {
+    watchers: usize,
+    // the last few generations recorded, oldest first
+    history: VecDeque<Recorded>,
+    // what to send for the latest generation recorded in each format, once it's been worked
+    // out
+    messages: HashMap<Format, Option<Encoded>>,
+}
+
+struct Recorded {
+    generation: u64,
+    ring: Vec<CellState>,
+    edited: bool,
+}
+
+impl Watched {
+    fn recorded(&self, generation: u64) -> Option<&Recorded> {
+        self.history.iter().find(|r| r.generation == generation)
+    }
+}
+
+impl Interest {
+    pub fn new() -> Interest {
+        Interest::default()
+    }
+
+    /// Note down the ring of each chunk being looked at, and whether it was edited, as of
+    /// `world`, which has just been stepped
+    pub fn record(&self, world: &World) {
+        let generation = world.generation();
+        let mut chunks = self.chunks.lock().unwrap();
+        for (&key, watched) in chunks.iter_mut() {
+            let origin = chunk_origin(key);
+            if watched.history.len() == HISTORY {
+                watched.history.pop_front();
+            }
+            watched.history.push_back(Recorded {
+                generation,
+                ring: world.copy_perimeter(origin.x, origin.y, CHUNK_SIZE, CHUNK_SIZE),
+                edited: world.was_edited(key),
+            });
+            watched.messages.clear();
+        }
+    }
+
+    /// Every chunk someone is looking at
+    pub fn watched(&self) -> Vec<ChunkKey> {
+        self.chunks.lock().unwrap().keys().copied().collect()
+    }
+
+    /// What to send a connection looking at `key` to bring it from the generation before
+    /// `world`s to `world`s, if anything, in `format`
+    pub fn update(&self, world: &World, key: ChunkKey, format: Format) -> Option<Encoded> {
+        let generation = world.generation();
+        let mut chunks = self.chunks.lock().unwrap();
+        let Some(watched) = chunks.get_mut(&key) else {
+            return Some(encode(&chunk_message(world, key), format));
+        };
+        let latest = watched.history.back().map(|r| r.generation) == Some(generation);
+        if let (true, Some(message)) = (latest, watched.messages.get(&format)) {
+            return message.clone();
+        }
+        let message = match watched.recorded(generation) {
+            // it's been looked at since, or was edited, so the ring isn't enough
+            None => Some(encode(&chunk_message(world, key), format)),
+            Some(r) if r.edited => Some(encode(&chunk_message(world, key), format)),
+            Some(r) => {
+                let before = watched.recorded(generation.wrapping_sub(1));
+                if before.is_some_and(|b| b.ring == r.ring) {
+                    None
+                } else {
+                    let origin = chunk_origin(key);
+                    let ring = FromServer::ChunkRing {
+                        generation,
+                        x: origin.x,
+                        y: origin.y,
+                        tiles: r.ring.clone(),
+                    };
+                    Some(encode(&ring, format))
+                }
+            }
+        };
+        if latest {
+            watched.messages.insert(format, message.clone());
+        }
+        message
+    }
+
+    fn watch(&self, keys: &[ChunkKey]) {
+        let mut chunks = self.chunks.lock().unwrap();
+        for &key in keys {
+            chunks
+                .entry(key)
+                .or_insert_with(|| Watched {
+                    watchers: 0,
+                    history: VecDeque::new(),
+                    messages: HashMap::new(),
+                })
+                .watchers += 1;
+        }
+    }
+
+    fn unwatch(&self, keys: &[ChunkKey]) {
+        let mut chunks = self.chunks.lock().unwrap();
+        for key in keys {
+            if let Some(watched) = chunks.get_mut(key) {
+                watched.watchers -= 1;
+                if watched.watchers == 0 {
+                    chunks.remove(key);
+                }
+            }
+        }
+    }
+}
+
+// a chunk's cells, row by row
+fn chunk_tiles(world: &World, key: ChunkKey) -> Vec<CellState> {
+    let origin = chunk_origin(key);
+    world
+        .copy_slice(origin.x, origin.y, CHUNK_SIZE, CHUNK_SIZE)
+        .concat()
+}
+
+fn chunk_message(world: &World, key: ChunkKey) -> FromServer {
+    let origin = chunk_origin(key);
+    FromServer::Chunk {
+        generation: world.generation(),
+        x: origin.x,
+        y: origin.y,
+        tiles: chunk_tiles(world, key),
+    }
+}
+
+/// The chunks one connection is looking at, counted in an `Interest` until dropped
+pub struct Subscription {
+    interest: Arc<Interest>,
+    // how the tiles of refreshes and pans are sent
+    encoding: Encoding,
+    // how updates are encoded
+    format: Format,
+    view: Rect,
+    keys: Vec<ChunkKey>,
+}
+
+impl Subscription {
+    pub fn new(interest: Arc<Interest>, encoding: Encoding, format: Format) -> Subscription {
+        Subscription {
+            interest,
+            encoding,
+            format,
+            view: Rect {
+                x: 0,
+                y: 0,
+                w: 0,
+                h: 0,
+            },
+            keys: Vec::new(),
+        }
+    }
+
+    /// Look at the chunks making up `view` instead
+    pub fn set_view(&mut self, view: Rect) {
+        let keys = view_chunks(chunk_view(view));
+        self.interest.watch(&keys);
+        self.interest.unwatch(&self.keys);
+        self.view = chunk_view(view);
+        self.keys = keys;
+    }
+
+    /// The view, grown to whole chunks
+    pub fn view(&self) -> Rect {
+        self.view
+    }
+
+    /// What to send to bring the connection from the generation before `world`s to
+    /// `world`s, ending with a `Tick`, and every `CHECKSUM_EVERY` generations a `Checksum`
+    pub fn updates(&self, world: &World) -> Vec<Encoded> {
+        let generation = world.generation();
+        let mut updates: Vec<_> = self
+            .keys
+            .iter()
+            .filter_map(|&key| self.interest.update(world, key, self.format))
+            .collect();
+        updates.push(encode(&FromServer::Tick { generation }, self.format));
+        if generation.is_multiple_of(CHECKSUM_EVERY) {
+            let checksum = FromServer::Checksum {
+                generation,
+                checksum: checksum(&self.tiles(world)),
+            };
+            updates.push(encode(&checksum, self.format));
+        }
+        updates
+    }
+
+    /// Everything in the view, for a connection that doesn't have the generation before
+    /// `world`s
+    pub fn refresh(&self, world: &World) -> FromServer {
+        self.refresh_from(world.generation(), self.tiles(world))
+    }
+
+    /// Everything in the view, from its `tiles` as of `generation`
+    pub fn refresh_from(&self, generation: u64, tiles: Vec<Vec<CellState>>) -> FromServer {
+        let Rect { x, y, .. } = self.view;
+        FromServer::FullRefresh {
+            generation,
+            x,
+            y,
+            tiles: Tiles::encode(tiles, self.encoding),
+        }
+    }
+
+    /// The cells of the view, row by row
+    pub fn tiles(&self, world: &World) -> Vec<Vec<CellState>> {
+        let Rect { x, y, w, h } = self.view;
+        world.copy_slice(x, y, w, h)
+    }
+
+    /// What to send a connection that has `world` in the view `from` to show it the view
+    /// instead, if anything
+    pub fn pan(&self, from: Rect, world: &World) -> Option<FromServer> {
+        if from == self.view {
+            return None;
+        }
+        let had: HashSet<_> = view_chunks(from).into_iter().collect();
+        let Rect { x, y, w, h } = self.view;
+        let chunks = self
+            .keys
+            .iter()
+            .filter(|key| !had.contains(key))
+            .map(|&key| {
+                let origin = chunk_origin(key);
+                let tiles = world.copy_slice(origin.x, origin.y, CHUNK_SIZE, CHUNK_SIZE);
+                (origin.x, origin.y, Tiles::encode(tiles, self.encoding))
+            })
+            .collect();
+        Some(FromServer::Pan {
+            generation: world.generation(),
+            x,
+            y,
+            w,
+            h,
+            chunks,
+        })
+    }
+
+    /// The generations from `from` to `world`s, as the rings of the chunks in view after
+    /// each, if they were all recorded and none of the chunks were edited
+    pub fn replay(&self, from: u64, world: &World) -> Option<FromServer> {
+        let generation = world.generation();
+        let count = generation.checked_sub(from).filter(|&c| c > 0)?;
+        let chunks = self.interest.chunks.lock().unwrap();
+        let mut rings = Vec::new();
+        for key in &self.keys {
+            let watched = chunks.get(key)?;
+            let mut before = watched.recorded(from).map(|r| &r.ring);
+            let mut changed = false;
+            let mut these = Vec::new();
+            for g in from + 1..=generation {
+                let r = watched.recorded(g).filter(|r| !r.edited)?;
+                changed |= before != Some(&r.ring);
+                before = Some(&r.ring);
+                these.push(r.ring.clone());
+            }
+            if changed {
+                let origin = chunk_origin(*key);
+                rings.push((origin.x, origin.y, these));
+            }
+        }
+        Some(FromServer::Ticks {
+            generation,
+            count: count as u32,
+            rings,
+        })
+    }
+
+    /// What changed in the view since it held the tiles `before`, for a connection that has
+    /// them but skipped the generations since, or everything in it if that's smaller
+    pub fn catch_up(&self, before: &[Vec<CellState>], to: &World) -> FromServer {
+        let Rect { x, y, w, h } = self.view;
+        let tiles = self.tiles(to);
+        let mut cells = Vec::new();
+        for (dy, (old, new)) in before.iter().zip(&tiles).enumerate() {
+            for (dx, (&a, &b)) in old.iter().zip(new).enumerate() {
+                if a != b {
+                    cells.push((dx as u16, dy as u16, b));
+                }
+            }
+        }
+        let generation = to.generation();
+        // a changed cell takes about twice the room of one sent in full
+        if cells.len() * 2 > w as usize * h as usize {
+            return FromServer::FullRefresh {
+                generation,
+                x,
+                y,
+                tiles: Tiles::encode(tiles, self.encoding),
+            };
+        }
+        FromServer::CatchUp {
+            generation,
+            x,
+            y,
+            cells,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.interest.unwatch(&self.keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i32, y: i32, w: i32, h: i32) -> Rect {
+        Rect { x, y, w, h }
+    }
+
+    #[test]
+    fn chunk_views() {
+        assert_eq!(chunk_view(rect(5, -5, 30, 10)), rect(0, -32, 64, 64));
+        assert_eq!(chunk_view(rect(5, 5, 0, 10)), rect(5, 5, 0, 0));
+        assert_eq!(
+            chunk_view(rect(0, 0, i32::MAX, 1)),
+            rect(0, 0, MAX_VIEW_CHUNKS * CHUNK_SIZE, CHUNK_SIZE)
+        );
+        let last = LAST_CHUNK * CHUNK_SIZE;
+        assert_eq!(
+            chunk_view(rect(i32::MAX - 5, i32::MAX - 5, 100, 100)),
+            rect(last, last, CHUNK_SIZE, CHUNK_SIZE)
+        );
+        assert_eq!(
+            chunk_view(rect(i32::MIN, i32::MIN, 1, 1)),
+            rect(i32::MIN, i32::MIN, CHUNK_SIZE, CHUNK_SIZE)
+        );
+        for view in [
+            rect(i32::MAX - 5, 0, 100, 1),
+            rect(i32::MIN, i32::MAX - 40, i32::MAX, i32::MAX),
+        ] {
+            let view = chunk_view(view);
+            assert!(view.x.checked_add(view.w).is_some(), "{:?}", view);
+            assert!(view.y.checked_add(view.h).is_some(), "{:?}", view);
+        }
+    }
+
+    #[test]
+    fn views_at_the_edge() {
+        let mut world = World::new();
+        // the last cell that can be seen
+        let corner = Point {
+            x: (LAST_CHUNK + 1) * CHUNK_SIZE - 1,
+            y: (LAST_CHUNK + 1) * CHUNK_SIZE - 1,
+        };
+        world.set_tile(corner, CellState::Wire).unwrap();
+        world
+            .set_tile(
+                Point {
+                    x: i32::MAX - 1,
+                    y: i32::MAX - 1,
+                },
+                CellState::Wire,
+            )
+            .unwrap();
+        let interest = Arc::new(Interest::new());
+        let mut subscription =
+            Subscription::new(interest.clone(), Encoding::Plain, Format::MessagePack);
+        subscription.set_view(rect(i32::MAX - 10, i32::MAX - 10, 64, 64));
+        world.set_generation(CHECKSUM_EVERY);
+        interest.record(&world);
+        assert!(subscription.updates(&world).len() >= 2);
+        let FromServer::FullRefresh { tiles, .. } = subscription.refresh(&world) else {
+            panic!("expected a refresh");
+        };
+        let tiles = tiles.decode().unwrap();
+        assert_eq!(tiles.len(), CHUNK_SIZE as usize);
+        assert_eq!(tiles[31][31], CellState::Wire);
+
+        let slice = world.copy_slice(i32::MAX - 2, i32::MAX - 2, 4, 4);
+        assert_eq!(slice.len(), 4);
+        assert_eq!(slice[1][1], CellState::Wire);
+        assert_eq!(slice[1][3], CellState::Empty);
+        assert_eq!(slice[3], [CellState::Empty; 4]);
+    }
+
+    // a wire along `y` from `x0` to `x1`, with an electron at its start heading along it
+    fn wire(world: &mut World, y: i32, x0: i32, x1: i32) {
+        for x in x0..=x1 {
+            world.set_tile(Point { x, y }, CellState::Wire).unwrap();
+        }
+        world.set_tile(Point { x: x0, y }, CellState::Dead).unwrap();
+        world
+            .set_tile(Point { x: x0 + 1, y }, CellState::Alive)
+            .unwrap();
+    }
+
+    // step `world`, recording each generation as the host does
+    fn step(world: &mut World, interest: &Interest, generations: u64) {
+        for _ in 0..generations {
+            world.step().unwrap();
+            interest.record(world);
+            world.clear_edited();
+        }
+    }
+
+    #[test]
+    fn replaying() {
+        let mut world = World::new();
+        // the electron crosses from the first chunk into the second, the third stays still
+        wire(&mut world, 5, 20, 40);
+        wire(&mut world, 40, 100, 101);
+        let interest = Arc::new(Interest::new());
+        let mut subscription =
+            Subscription::new(interest.clone(), Encoding::Plain, Format::MessagePack);
+        subscription.set_view(rect(0, 0, 3 * CHUNK_SIZE, 2 * CHUNK_SIZE));
+        step(&mut world, &interest, 4);
+
+        let mut rings = Vec::new();
+        for _ in 0..10 {
+            step(&mut world, &interest, 1);
+            rings.push(world.copy_perimeter(0, 0, CHUNK_SIZE, CHUNK_SIZE));
+        }
+        let Some(FromServer::Ticks {
+            generation,
+            count,
+            rings: replayed,
+        }) = subscription.replay(4, &world)
+        else {
+            panic!("expected ticks");
+        };
+        assert_eq!((generation, count), (14, 10));
+        // only the chunks whose rings changed
+        let chunks: Vec<_> = replayed.iter().map(|&(x, y, _)| (x, y)).collect();
+        assert_eq!(chunks, [(0, 0), (CHUNK_SIZE, 0)]);
+        assert_eq!(replayed[0].2, rings);
+        assert_eq!(subscription.replay(14, &world), None);
+        assert_eq!(subscription.replay(20, &world), None);
+
+        // generations no longer recorded can't be replayed
+        step(&mut world, &interest, HISTORY as u64);
+        assert_eq!(subscription.replay(13, &world), None);
+        assert!(subscription.replay(14, &world).is_some());
+
+        // nor can ones where a chunk in view was edited
+        world
+            .edit_tile(Point { x: 70, y: 50 }, CellState::Wire)
+            .unwrap();
+        let edited = world.generation();
+        step(&mut world, &interest, 2);
+        assert_eq!(subscription.replay(edited, &world), None);
+        assert!(subscription.replay(edited + 1, &world).is_some());
+    }
+
+    #[test]
+    fn panning() {
+        let mut world = World::new();
+        wire(&mut world, 5, 20, 40);
+        let interest = Arc::new(Interest::new());
+        let mut subscription =
+            Subscription::new(interest.clone(), Encoding::Plain, Format::MessagePack);
+        subscription.set_view(rect(0, 0, CHUNK_SIZE, CHUNK_SIZE));
+        let from = subscription.view();
+        assert_eq!(subscription.pan(from, &world), None);
+
+        subscription.set_view(rect(10, -10, CHUNK_SIZE, CHUNK_SIZE));
+        let Some(FromServer::Pan {
+            generation,
+            x,
+            y,
+            w,
+            h,
+            chunks,
+        }) = subscription.pan(from, &world)
+        else {
+            panic!("expected a pan");
+        };
+        assert_eq!(generation, 0);
+        assert_eq!(
+            (x, y, w, h),
+            (0, -CHUNK_SIZE, 2 * CHUNK_SIZE, 2 * CHUNK_SIZE)
+        );
+        // everything but the chunk that was already in view
+        let mut sent: Vec<_> = chunks.iter().map(|&(x, y, _)| (x, y)).collect();
+        sent.sort();
+        assert_eq!(
+            sent,
+            [(0, -CHUNK_SIZE), (CHUNK_SIZE, -CHUNK_SIZE), (CHUNK_SIZE, 0)]
+        );
+        let (_, _, tiles) = chunks
+            .iter()
+            .find(|&&(x, y, _)| (x, y) == (CHUNK_SIZE, 0))
+            .unwrap();
+        assert_eq!(
+            tiles.clone().decode().unwrap(),
+            world.copy_slice(CHUNK_SIZE, 0, CHUNK_SIZE, CHUNK_SIZE)
+        );
+        // the chunks no longer in view stop being watched
+        subscription.set_view(rect(CHUNK_SIZE, 0, 1, 1));
+        assert_eq!(interest.watched(), [(1, 0)]);
+    }
+
+    #[test]
+    fn checksums() {
+        let mut world = World::new();
+        wire(&mut world, 5, 20, 40);
+        let interest = Arc::new(Interest::new());
+        let mut subscription =
+            Subscription::new(interest.clone(), Encoding::Plain, Format::MessagePack);
+        subscription.set_view(rect(0, 0, 2 * CHUNK_SIZE, CHUNK_SIZE));
+        step(&mut world, &interest, CHECKSUM_EVERY - 1);
+        let last = |updates: Vec<Encoded>| {
+            rmp_serde::from_slice::<FromServer>(updates.last().unwrap()).unwrap()
+        };
+        assert_eq!(
+            last(subscription.updates(&world)),
+            FromServer::Tick {
+                generation: CHECKSUM_EVERY - 1
+            }
+        );
+        step(&mut world, &interest, 1);
+        let FromServer::Checksum {
+            generation,
+            checksum: sum,
+        } = last(subscription.updates(&world))
+        else {
+            panic!("expected a checksum");
+        };
+        assert_eq!(generation, CHECKSUM_EVERY);
+        let tiles = world.copy_slice(0, 0, 2 * CHUNK_SIZE, CHUNK_SIZE);
+        assert_eq!(sum, checksum(&tiles));
+    }
+}
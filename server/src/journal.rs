@@ -0,0 +1,422 @@
+//! An append-only log of the edits made to the running world.
+//!
+//! Replaying a journal on top of the snapshot it started from reproduces the world at any
+//! later generation, since stepping is deterministic. The file is a header, `b"WUJN"` and a
+//! u16 version, followed by records, each a tag byte and then:
+//!
+//! ```text
+//! 0, edit        u64 generation, i32 x, i32 y, u8 cell as `CellState::to_bits`
+//! 1, checkpoint  u64 generation, u64 `World::state_hash` at the start of that generation
+//! ```
+//!
+//! All integers are little endian. Records are in the order things happened to the world, so
+//! an edit is applied at its generation before the world steps past it.
+//!
+//! Once an autosave holds everything the journal does, the journal is cut back to its header
+//! and starts again with a checkpoint at the autosave's generation, so it only ever holds the
+//! edits since the newest snapshot.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use wire_universe::{CellState, Point};
+
+use crate::world::{region_fits, World};
+
+const MAGIC: &[u8; 4] = b"WUJN";
+const VERSION: u16 = 1;
+const TAG_EDIT: u8 = 0;
+const TAG_CHECKPOINT: u8 = 1;
+const HEADER_LEN: u64 = 6;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Entry {
+    Edit {
+        generation: u64,
+        pos: Point,
+        cell: CellState,
+    },
+    Checkpoint {
+        generation: u64,
+        hash: u64,
+    },
+}
+
+impl Entry {
+    pub fn generation(&self) -> u64 {
+        match *self {
+            Entry::Edit { generation, .. } | Entry::Checkpoint { generation, .. } => generation,
+        }
+    }
+}
+
+pub struct Journal {
+    out: BufWriter<File>,
+}
+
+impl Journal {
+    /// Open a journal for appending, creating it if it doesn't exist
+    pub fn open(path: &Path) -> Result<Journal> {
+        let context = || format!("Failed to open journal {}", path.display());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(context)?;
+        if file.metadata().with_context(context)?.len() == 0 {
+            file.write_all(MAGIC).with_context(context)?;
+            file.write_all(&VERSION.to_le_bytes())
+                .with_context(context)?;
+        } else {
+            // drop any record cut short by a crash, so new records don't follow garbage
+            let mut reader = JournalReader::new(BufReader::new(&file)).with_context(context)?;
+            for entry in &mut reader {
+                entry.with_context(context)?;
+            }
+            if reader.truncated {
+                file.set_len(reader.position).with_context(context)?;
+            }
+        }
+        Ok(Journal {
+            out: BufWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, entry: Entry) -> Result<()> {
+        match entry {
+            Entry::Edit {
+                generation,
+                pos,
+                cell,
+            } => {
+                self.out.write_all(&[TAG_EDIT])?;
+                self.out.write_all(&generation.to_le_bytes())?;
+                self.out.write_all(&pos.x.to_le_bytes())?;
+                self.out.write_all(&pos.y.to_le_bytes())?;
+                self.out.write_all(&[cell.to_bits()])?;
+            }
+            Entry::Checkpoint { generation, hash } => {
+                self.out.write_all(&[TAG_CHECKPOINT])?;
+                self.out.write_all(&generation.to_le_bytes())?;
+                self.out.write_all(&hash.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop every record, for when a snapshot has made them all redundant
+    pub fn clear(&mut self) -> Result<()> {
+        self.out.flush()?;
+        // the file is opened for appending, so later records go straight after the header
+        self.out.get_ref().set_len(HEADER_LEN)?;
+        Ok(())
+    }
+
+    /// Push buffered records to the operating system
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+}
+
+fn read_header<R: Read>(r: &mut R) -> Result<()> {
+    let mut header = [0; 6];
+    r.read_exact(&mut header)
+        .context("Journal too short for its header")?;
+    ensure!(&header[..4] == MAGIC, "Not a journal: bad magic");
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    ensure!(
+        version == VERSION,
+        "Unsupported journal version {}",
+        version
+    );
+    Ok(())
+}
+
+/// Reads entries one at a time. A record cut short at the end of the file, as a crash
+/// while writing leaves behind, ends the entries and sets `truncated`.
+pub struct JournalReader<R> {
+    input: R,
+    pub truncated: bool,
+    /// the byte offset just past the last complete record read
+    pub position: u64,
+}
+
+impl JournalReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).context(format!("Failed to open journal {}", path.display()))?;
+        JournalReader::new(BufReader::new(file))
+            .context(format!("Failed to read journal {}", path.display()))
+    }
+}
+
+impl<R: BufRead> JournalReader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        read_header(&mut input)?;
+        Ok(JournalReader {
+            input,
+            truncated: false,
+            position: HEADER_LEN,
+        })
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        let mut tag = [0];
+        if self.input.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let mut body = [0; 17];
+        let body = match tag[0] {
+            TAG_EDIT => &mut body[..17],
+            TAG_CHECKPOINT => &mut body[..16],
+            t => bail!("Unknown journal record type {}", t),
+        };
+        match self.input.read_exact(body) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.truncated = true;
+                return Ok(None);
+            }
+            r => r?,
+        }
+        self.position += 1 + body.len() as u64;
+        let generation = u64::from_le_bytes(body[..8].try_into().unwrap());
+        Ok(Some(match tag[0] {
+            TAG_EDIT => Entry::Edit {
+                generation,
+                pos: Point {
+                    x: i32::from_le_bytes(body[8..12].try_into().unwrap()),
+                    y: i32::from_le_bytes(body[12..16].try_into().unwrap()),
+                },
+                cell: CellState::from_bits(body[16]),
+            },
+            _ => Entry::Checkpoint {
+                generation,
+                hash: u64::from_le_bytes(body[8..16].try_into().unwrap()),
+            },
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for JournalReader<R> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.truncated {
+            return None;
+        }
+        self.next_entry().transpose()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub edits: usize,
+    pub checkpoints: usize,
+}
+
+/// Apply journal entries to `world`, stepping it to each entry's generation. Entries from
+/// before the world's generation are already part of it and are skipped. Stops before the
+/// first entry past `until`, and steps to `until` if given. Fails on the first checkpoint
+/// that doesn't match or edit outside the world.
+pub fn replay<I>(world: &mut World, entries: I, until: Option<u64>) -> Result<ReplayReport>
+where
+    I: IntoIterator<Item = Result<Entry>>,
+{
+    let mut report = ReplayReport::default();
+    for entry in entries {
+        let entry = entry?;
+        let generation = entry.generation();
+        if until.is_some_and(|until| generation > until) {
+            break;
+        }
+        if generation < world.generation() {
+            continue;
+        }
+        while world.generation() < generation {
+            world.step()?;
+        }
+        match entry {
+            Entry::Edit { pos, cell, .. } => {
+                // the same bounds edits from clients are held to, which the world relies on
+                ensure!(
+                    region_fits(pos.x, pos.y, 1, 1),
+                    "Edit at ({}, {}) in generation {} is out of range",
+                    pos.x,
+                    pos.y,
+                    generation
+                );
+                world.set_tile(pos, cell)?;
+                report.edits += 1;
+            }
+            Entry::Checkpoint { hash, .. } => {
+                let actual = world.state_hash();
+                if actual != hash {
+                    return Err(anyhow!(
+                        "State hash mismatch at generation {}: journal has {:016x}, replay has {:016x}",
+                        generation,
+                        hash,
+                        actual
+                    ));
+                }
+                report.checkpoints += 1;
+            }
+        }
+    }
+    if let Some(until) = until {
+        while world.generation() < until {
+            world.step()?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::temp_dir, world::sample_world};
+
+    fn read_all(path: &Path) -> (Vec<Entry>, bool) {
+        let mut reader = JournalReader::open(path).unwrap();
+        let entries = (&mut reader).collect::<Result<Vec<_>>>().unwrap();
+        (entries, reader.truncated)
+    }
+
+    // a run of the world with edits, journaled with a checkpoint at the start of each
+    // generation, along with the world it ends on
+    fn history() -> (Vec<Entry>, World) {
+        let mut world = sample_world();
+        let mut entries = Vec::new();
+        for n in 0..6 {
+            let generation = world.generation();
+            entries.push(Entry::Checkpoint {
+                generation,
+                hash: world.state_hash(),
+            });
+            let pos = Point { x: 3, y: n };
+            world.set_tile(pos, CellState::Wire).unwrap();
+            entries.push(Entry::Edit {
+                generation,
+                pos,
+                cell: CellState::Wire,
+            });
+            world.step().unwrap();
+        }
+        (entries, world)
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temp_dir(&[]);
+        let path = dir.path().join("journal");
+        let (entries, end) = history();
+        let mut journal = Journal::open(&path).unwrap();
+        for &entry in &entries {
+            journal.record(entry).unwrap();
+        }
+        journal.flush().unwrap();
+        drop(journal);
+        assert_eq!(read_all(&path), (entries.clone(), false));
+
+        let mut world = sample_world();
+        let report = replay(
+            &mut world,
+            entries.into_iter().map(Ok),
+            Some(end.generation()),
+        )
+        .unwrap();
+        assert_eq!((report.edits, report.checkpoints), (6, 6));
+        assert_eq!(world.state_hash(), end.state_hash());
+    }
+
+    #[test]
+    fn checkpoint_mismatch() {
+        let (mut entries, _) = history();
+        // an edit lost between two checkpoints
+        entries.remove(5);
+        let err = replay(&mut sample_world(), entries.into_iter().map(Ok), None).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("State hash mismatch at generation 3"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn truncated_record() {
+        let dir = temp_dir(&[]);
+        let path = dir.path().join("journal");
+        let (entries, _) = history();
+        let mut journal = Journal::open(&path).unwrap();
+        for &entry in &entries[..3] {
+            journal.record(entry).unwrap();
+        }
+        journal.flush().unwrap();
+        drop(journal);
+        let complete = std::fs::metadata(&path).unwrap().len();
+        // half of the next edit record, as a crash might leave behind
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[TAG_EDIT, 1, 2, 3]).unwrap();
+        drop(file);
+        assert_eq!(read_all(&path), (entries[..3].to_vec(), true));
+
+        // reopening drops the partial record, so new records can follow
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete);
+        journal.record(entries[3]).unwrap();
+        journal.flush().unwrap();
+        assert_eq!(read_all(&path), (entries[..4].to_vec(), false));
+    }
+
+    #[test]
+    fn malformed() {
+        let bad: [&[u8]; 4] = [b"", b"WUJ", b"NOPE\x01\x00", b"WUJN\x02\x00"];
+        for data in bad {
+            assert!(JournalReader::new(data).is_err(), "{:?}", data);
+        }
+        let mut reader = JournalReader::new(&b"WUJN\x01\x00\x07"[..]).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn out_of_range_edit() {
+        let dir = temp_dir(&[]);
+        for (x, y) in [(i32::MAX, 0), (0, i32::MIN), (i32::MIN, i32::MAX)] {
+            let path = dir.path().join("journal");
+            let mut journal = Journal::open(&path).unwrap();
+            journal.clear().unwrap();
+            journal
+                .record(Entry::Edit {
+                    generation: 0,
+                    pos: Point { x, y },
+                    cell: CellState::Wire,
+                })
+                .unwrap();
+            journal.flush().unwrap();
+            drop(journal);
+            let reader = JournalReader::open(&path).unwrap();
+            let err = replay(&mut sample_world(), reader, None).unwrap_err();
+            assert!(err.to_string().contains("out of range"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn clear() {
+        let dir = temp_dir(&[]);
+        let path = dir.path().join("journal");
+        let (entries, _) = history();
+        let mut journal = Journal::open(&path).unwrap();
+        for &entry in &entries {
+            journal.record(entry).unwrap();
+        }
+        journal.clear().unwrap();
+        journal.record(entries[4]).unwrap();
+        journal.flush().unwrap();
+        assert_eq!(read_all(&path), (vec![entries[4]], false));
+    }
+}
@@ -0,0 +1,291 @@
+//! Building one world out of several pattern files.
+//!
+//! A manifest is a TOML file listing patterns, each placed at an offset and optionally
+//! mirrored and rotated:
+//!
+//! ```toml
+//! [[pattern]]
+//! file = "clock.wi"     # relative to the manifest, in any format `World::load` reads
+//! x = 10
+//! y = -4
+//! rotate = 90           # degrees clockwise, one of 0, 90, 180 and 270
+//! flip = "horizontal"   # or "vertical"
+//! name = "clock"
+//! ```
+//!
+//! A pattern file may itself be a manifest, which is how one manifest includes another.
+//! Patterns are transformed within the box spanning the origin and all of their cells, then
+//! moved by the offset, so an untransformed pattern keeps its own coordinates relative to
+//! the offset. Two patterns putting cells in the same place is an error.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use wire_universe::{CellState, Point, Rotation, Transform};
+
+use crate::world::World;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    pattern: Vec<Pattern>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Pattern {
+    file: PathBuf,
+    #[serde(default)]
+    x: i32,
+    #[serde(default)]
+    y: i32,
+    #[serde(default)]
+    rotate: u32,
+    flip: Option<Flip>,
+    name: Option<String>,
+}
+
+impl Pattern {
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("`{}' ({})", name, self.file.display()),
+            None => self.file.display().to_string(),
+        }
+    }
+
+    fn transform(&self) -> Result<Transform> {
+        let rotation = match self.rotate {
+            0 => Rotation::R0,
+            90 => Rotation::R90,
+            180 => Rotation::R180,
+            270 => Rotation::R270,
+            r => bail!("Rotation must be 0, 90, 180 or 270, not {}", r),
+        };
+        Ok(match self.flip {
+            None => Transform {
+                mirror: false,
+                rotation,
+            },
+            Some(Flip::Horizontal) => Transform {
+                mirror: true,
+                rotation,
+            },
+            // mirroring top to bottom is mirroring left to right and turning halfway around
+            Some(Flip::Vertical) => Transform {
+                mirror: true,
+                rotation: match rotation {
+                    Rotation::R0 => Rotation::R180,
+                    Rotation::R90 => Rotation::R270,
+                    Rotation::R180 => Rotation::R0,
+                    Rotation::R270 => Rotation::R90,
+                },
+            },
+        })
+    }
+}
+
+/// Load the world described by the manifest at `path`
+pub fn load_manifest(path: &Path) -> Result<World> {
+    load_nested(path, &mut Vec::new())
+}
+
+// `stack` holds the manifests currently being loaded, to catch includes that loop
+fn load_nested(path: &Path, stack: &mut Vec<PathBuf>) -> Result<World> {
+    let canonical = path
+        .canonicalize()
+        .context(format!("Failed to read manifest {}", path.display()))?;
+    if stack.contains(&canonical) {
+        bail!("Manifest {} includes itself", path.display());
+    }
+    let text = std::fs::read_to_string(path)
+        .context(format!("Failed to read manifest {}", path.display()))?;
+    let manifest: Manifest =
+        toml::from_str(&text).context(format!("Failed to parse manifest {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    stack.push(canonical);
+    // which pattern each placed cell came from
+    let mut placed: HashMap<Point, (CellState, usize)> = HashMap::new();
+    // the first clash between each pair of patterns, and how many cells clashed
+    let mut overlaps: HashMap<(usize, usize), (Point, usize)> = HashMap::new();
+    for (n, pattern) in manifest.pattern.iter().enumerate() {
+        let file = dir.join(&pattern.file);
+        let world = if is_manifest(&file) {
+            load_nested(&file, stack)
+        } else {
+            World::load(&file)
+        }
+        .context(format!("In pattern {}", pattern.label()))?;
+        let transform = pattern
+            .transform()
+            .context(format!("In pattern {}", pattern.label()))?;
+        let (bx, by, bw, bh) = world.bounds().unwrap_or((0, 0, 1, 1));
+        let (x0, y0) = (bx.min(0), by.min(0));
+        let (Some(w), Some(h)) = (
+            (bx + bw).max(1).checked_sub(x0),
+            (by + bh).max(1).checked_sub(y0),
+        ) else {
+            bail!("Pattern {} is too large", pattern.label());
+        };
+        for cell in world.cells() {
+            let (p, cell) = cell?;
+            let q = transform.apply(
+                Point {
+                    x: p.x - x0,
+                    y: p.y - y0,
+                },
+                w,
+                h,
+            );
+            // leave room for the neighbours of every cell
+            let inside = |c: i32| (c > i32::MIN && c < i32::MAX).then_some(c);
+            let (Some(x), Some(y)) = (
+                q.x.checked_add(pattern.x).and_then(inside),
+                q.y.checked_add(pattern.y).and_then(inside),
+            ) else {
+                bail!("Pattern {} goes out of bounds", pattern.label());
+            };
+            let q = Point { x, y };
+            if let Some(&(_, other)) = placed.get(&q) {
+                overlaps.entry((other, n)).or_insert((q, 0)).1 += 1;
+            } else {
+                placed.insert(q, (cell, n));
+            }
+        }
+    }
+    stack.pop();
+
+    if !overlaps.is_empty() {
+        let mut pairs: Vec<_> = overlaps.into_iter().collect();
+        pairs.sort_by_key(|&(pair, _)| pair);
+        let mut msg = format!("Overlapping patterns in manifest {}:", path.display());
+        for ((a, b), (p, count)) in pairs {
+            write!(
+                msg,
+                "\n  {} and {} overlap in {} cells, first at ({}, {})",
+                manifest.pattern[a].label(),
+                manifest.pattern[b].label(),
+                count,
+                p.x,
+                p.y
+            )
+            .unwrap();
+        }
+        return Err(anyhow!(msg));
+    }
+    let mut world = World::new();
+    for (p, (cell, _)) in placed {
+        world.set_tile(p, cell)?;
+    }
+    Ok(world)
+}
+
+pub fn is_manifest(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "toml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sorted_cells as cells, temp_dir};
+
+    const LINE: &str = "2 1\n#@\n";
+
+    #[test]
+    fn places_patterns() {
+        let dir = temp_dir(&[
+            ("line.wi", LINE),
+            (
+                "main.toml",
+                "[[pattern]]\nfile = \"line.wi\"\nx = 10\ny = -4\n\n\
+                     [[pattern]]\nfile = \"line.wi\"\ny = 5\nrotate = 90\n\n\
+                     [[pattern]]\nfile = \"line.wi\"\nx = 20\nflip = \"horizontal\"\n",
+            ),
+        ]);
+        let world = load_manifest(&dir.path().join("main.toml")).unwrap();
+        assert_eq!(
+            cells(&world),
+            [
+                (10, -4, CellState::Wire),
+                (11, -4, CellState::Alive),
+                (20, 0, CellState::Alive),
+                (21, 0, CellState::Wire),
+                (0, 5, CellState::Wire),
+                (0, 6, CellState::Alive),
+            ]
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let dir = temp_dir(&[
+            ("line.wi", LINE),
+            ("inner.toml", "[[pattern]]\nfile = \"line.wi\"\nx = 1\n"),
+            ("outer.toml", "[[pattern]]\nfile = \"inner.toml\"\ny = 2\n"),
+            ("loop.toml", "[[pattern]]\nfile = \"loop.toml\"\n"),
+        ]);
+        let world = load_manifest(&dir.path().join("outer.toml")).unwrap();
+        assert_eq!(
+            cells(&world),
+            [(1, 2, CellState::Wire), (2, 2, CellState::Alive)]
+        );
+        let err = load_manifest(&dir.path().join("loop.toml")).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("includes itself"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let dir = temp_dir(&[
+            ("line.wi", LINE),
+            (
+                "overlap.toml",
+                "[[pattern]]\nfile = \"line.wi\"\n[[pattern]]\nfile = \"line.wi\"\nx = 1\n",
+            ),
+            (
+                "rotate.toml",
+                "[[pattern]]\nfile = \"line.wi\"\nrotate = 45\n",
+            ),
+            ("unknown.toml", "[[pattern]]\nfile = \"line.wi\"\nz = 1\n"),
+            ("missing.toml", "[[pattern]]\nfile = \"nowhere.wi\"\n"),
+            (
+                "edge.toml",
+                "[[pattern]]\nfile = \"line.wi\"\nx = 2147483646\n",
+            ),
+            (
+                "under.toml",
+                "[[pattern]]\nfile = \"line.wi\"\ny = -2147483648\n",
+            ),
+            ("syntax.toml", "[[pattern]\n"),
+        ]);
+        for (file, expected) in [
+            ("overlap.toml", "overlap in 1 cells, first at (1, 0)"),
+            ("rotate.toml", "not 45"),
+            ("unknown.toml", "unknown field"),
+            ("missing.toml", "nowhere.wi"),
+            ("edge.toml", "out of bounds"),
+            ("under.toml", "out of bounds"),
+            ("syntax.toml", "Failed to parse"),
+        ] {
+            let err = load_manifest(&dir.path().join(file)).unwrap_err();
+            let message = format!("{:#}", err);
+            assert!(message.contains(expected), "{}: {}", file, message);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Private copies of the shared world, for trying out changes before making them for
+//! everyone.
+//!
+//! A connection can fork a rectangle of the shared world, or all of it, into a sandbox with
+//! its own clock, which can be paused, stepped and sped up or slowed down. Merging makes the
+//! edits done in the sandbox to the shared world, rather than copying the sandbox back over
+//! it, since the shared world has carried on in the meantime.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use wire_universe::{
+    proto::{FromServer, Rect},
+    CellState, Point,
+};
+
+use crate::{
+    host::CellModification,
+    interest::Interest,
+    world::{region_fits, World},
+};
+
+/// The most cells a sandbox may hold, forked or edited in
+pub const MAX_SANDBOX_CELLS: usize = 4_000_000;
+/// The most generations one `Step` may advance by
+pub const MAX_STEP: u32 = 1000;
+/// The most cells one `Step` may update, over all the generations it advances by, so a big
+/// sandbox advances by fewer
+pub const MAX_STEP_WORK: usize = 50_000_000;
+const MIN_TICK: Duration = Duration::from_millis(10);
+const MAX_TICK: Duration = Duration::from_secs(60);
+
+pub(crate) struct Sandbox {
+    pub world: World,
+    /// the chunks of the sandbox being looked at, by its one connection
+    pub interest: Arc<Interest>,
+    paused: bool,
+    tick: Duration,
+    clock: Interval,
+    // the last thing written to each edited tile
+    edits: HashMap<Point, CellState>,
+}
+
+impl Sandbox {
+    /// Copy `region` of `shared`, or all of it, running at the same speed
+    pub fn fork(shared: &World, region: Option<Rect>, tick: Duration) -> Result<Sandbox> {
+        let mut world = World::new();
+        match region {
+            Some(Rect { x, y, w, h }) => {
+                if !region_fits(x, y, w, h) || w as usize * h as usize > MAX_SANDBOX_CELLS {
+                    bail!("Can't fork a {}x{} region at ({}, {})", w, h, x, y);
+                }
+                for (dy, row) in shared.copy_slice(x, y, w, h).into_iter().enumerate() {
+                    for (dx, cell) in row.into_iter().enumerate() {
+                        if cell != CellState::Empty {
+                            let pos = Point {
+                                x: x + dx as i32,
+                                y: y + dy as i32,
+                            };
+                            world.set_tile(pos, cell)?;
+                        }
+                    }
+                }
+            }
+            None => {
+                for (count, cell) in shared.cells().enumerate() {
+                    if count == MAX_SANDBOX_CELLS {
+                        bail!("The world is too big to fork all of");
+                    }
+                    let (pos, cell) = cell?;
+                    world.set_tile(pos, cell)?;
+                }
+            }
+        }
+        world.set_generation(shared.generation());
+        let tick = tick.clamp(MIN_TICK, MAX_TICK);
+        Ok(Sandbox {
+            world,
+            interest: Arc::new(Interest::new()),
+            paused: false,
+            tick,
+            clock: clock(tick),
+            edits: HashMap::new(),
+        })
+    }
+
+    pub fn status(&self) -> FromServer {
+        FromServer::SandboxStatus {
+            paused: self.paused,
+            tick_ms: self.tick.as_millis() as u64,
+            generation: self.world.generation(),
+        }
+    }
+
+    pub fn set_tile(&mut self, pos: Point, cell: CellState) -> Result<()> {
+        if cell != CellState::Empty
+            && self.world.resident() >= MAX_SANDBOX_CELLS
+            && self.world.get_tile_out(pos) == CellState::Empty
+        {
+            bail!(
+                "The sandbox can't hold more than {} cells",
+                MAX_SANDBOX_CELLS
+            );
+        }
+        self.world.edit_tile(pos, cell)?;
+        self.edits.insert(pos, cell);
+        Ok(())
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.clock = clock(self.tick);
+        }
+    }
+
+    pub fn set_speed(&mut self, tick: Duration) {
+        self.tick = tick.clamp(MIN_TICK, MAX_TICK);
+        self.clock = clock(self.tick);
+    }
+
+    /// Advance by up to `MAX_STEP` generations, and fewer if that would be more than
+    /// `MAX_STEP_WORK`, but always at least one, recording each in `interest`
+    pub fn step(&mut self, generations: u32) -> Result<()> {
+        let most = (MAX_STEP_WORK / self.world.resident().max(1)).clamp(1, MAX_STEP as usize);
+        for _ in 0..generations.min(most as u32) {
+            self.world.step()?;
+            self.interest.record(&self.world);
+            self.world.clear_edited();
+        }
+        Ok(())
+    }
+
+    /// Wait until the sandbox is due its next generation, which is never while paused
+    pub async fn tick(&mut self) {
+        if self.paused {
+            std::future::pending::<()>().await;
+        }
+        self.clock.tick().await;
+    }
+
+    /// The edits to make to the shared world to merge the sandbox into it
+    pub fn into_edits(self) -> Vec<CellModification> {
+        self.edits
+            .into_iter()
+            .map(|(Point { x, y }, cell)| CellModification { x, y, cell })
+            .collect()
+    }
+}
+
+fn clock(tick: Duration) -> Interval {
+    let mut clock = interval_at(Instant::now() + tick, tick);
+    // a slow connection shouldn't make the sandbox race to catch up
+    clock.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    clock
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::sorted_cells, world::sample_world};
+
+    const TICK: Duration = Duration::from_millis(100);
+
+    fn region(x: i32, y: i32, w: i32, h: i32) -> Option<Rect> {
+        Some(Rect { x, y, w, h })
+    }
+
+    #[tokio::test]
+    async fn fork_region() {
+        let shared = sample_world();
+        let sandbox = Sandbox::fork(&shared, region(1, 0, 2, 3), TICK).unwrap();
+        assert_eq!(
+            sorted_cells(&sandbox.world),
+            [
+                (1, 0, CellState::Alive),
+                (2, 1, CellState::Wire),
+                (1, 2, CellState::Wire)
+            ]
+        );
+        let whole = Sandbox::fork(&shared, None, TICK).unwrap();
+        assert_eq!(whole.world.state_hash(), shared.state_hash());
+    }
+
+    #[tokio::test]
+    async fn fork_bad_regions() {
+        let shared = sample_world();
+        for region in [
+            region(0, 0, 0, 5),
+            region(0, 0, 5, -1),
+            region(0, 0, 4000, 4000),
+            region(i32::MAX - 10, 0, 11, 11),
+            region(0, i32::MAX - 1, 1, 2),
+            region(i32::MIN, 0, 10, 10),
+            region(0, i32::MIN, 10, 10),
+            region(i32::MAX, i32::MAX, i32::MAX, i32::MAX),
+        ] {
+            assert!(
+                Sandbox::fork(&shared, region, TICK).is_err(),
+                "{:?}",
+                region
+            );
+        }
+        assert!(Sandbox::fork(&shared, region(i32::MAX - 10, i32::MIN + 1, 10, 10), TICK).is_ok());
+    }
+
+    #[tokio::test]
+    async fn step_work_is_bounded() {
+        let mut sandbox = Sandbox::fork(&World::new(), None, TICK).unwrap();
+        sandbox.step(MAX_STEP + 5).unwrap();
+        assert_eq!(sandbox.world.generation(), MAX_STEP as u64);
+
+        // a line of wire big enough that `MAX_STEP` generations would be too much work
+        let cells = MAX_STEP_WORK / MAX_STEP as usize * 2;
+        for x in 0..cells as i32 {
+            sandbox
+                .set_tile(Point { x, y: 0 }, CellState::Wire)
+                .unwrap();
+        }
+        sandbox.step(MAX_STEP).unwrap();
+        assert_eq!(sandbox.world.generation(), MAX_STEP as u64 * 3 / 2);
+    }
+}
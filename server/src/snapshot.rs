@@ -0,0 +1,352 @@
+//! The native binary save format.
+//!
+//! A snapshot is a header followed by the non-empty chunks of the world:
+//!
+//! ```text
+//! magic       b"WUSN"
+//! version     u16
+//! flags       u16, bit 0 set if a checksum trailer is present
+//! rule        u8, only `RULE_WIREWORLD` so far
+//! generation  u64
+//! chunks      u32 count, then for each chunk:
+//!   x, y      i32 chunk coordinates, in units of `CHUNK_SIZE` cells
+//!   encoding  u8, `ENCODING_PACKED` or `ENCODING_RLE`
+//!   length    u32 byte length of the data
+//!   data      the chunk's cells row by row, encoded with `wire_universe::pack`
+//! checksum    u32 crc32 of everything before it, if flagged
+//! ```
+//!
+//! All integers are little endian.
+
+use std::{collections::HashMap, io::Write, path::Path};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use wire_universe::{
+    pack::{pack_2bit, rle_decode, rle_encode, unpack_2bit},
+    CellState, Point,
+};
+
+use crate::world::{
+    paging::{chunk_index, chunk_key, CHUNK_CELLS, CHUNK_SIZE},
+    World,
+};
+
+const MAGIC: &[u8; 4] = b"WUSN";
+const VERSION: u16 = 1;
+const FLAG_CHECKSUM: u16 = 1;
+pub const RULE_WIREWORLD: u8 = 0;
+const ENCODING_PACKED: u8 = 0;
+const ENCODING_RLE: u8 = 1;
+// bytes in a chunk before its data
+const CHUNK_HEADER: usize = 4 + 4 + 1 + 4;
+
+// the first cell coordinate of chunk `c`, if every cell in it and every neighbour of those cells
+// is in range: `World::set_tile` looks one cell past each side
+fn chunk_origin(c: i32) -> Option<i32> {
+    let origin = c.checked_mul(CHUNK_SIZE)?;
+    (origin > i32::MIN && origin.checked_add(CHUNK_SIZE).is_some()).then_some(origin)
+}
+
+// bounds checked reads over a byte slice, so malformed input becomes an error and not a panic
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| anyhow!("Unexpected end of snapshot at byte {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+}
+
+impl World {
+    /// Write a snapshot with a checksum to `path`
+    pub fn save_snapshot(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)
+            .context(format!("Failed to create snapshot {}", path.display()))?;
+        let mut out = std::io::BufWriter::new(file);
+        self.write_snapshot(&mut out, true)
+            .and_then(|_| Ok(out.flush()?))
+            .context(format!("Failed to write snapshot {}", path.display()))
+    }
+
+    pub fn load_snapshot(path: &Path) -> Result<World> {
+        let data =
+            std::fs::read(path).context(format!("Failed to read snapshot {}", path.display()))?;
+        World::read_snapshot(&data).context(format!("Failed to parse snapshot {}", path.display()))
+    }
+
+    pub fn write_snapshot<W: Write>(&self, mut out: W, checksum: bool) -> Result<()> {
+        let mut chunks: HashMap<(i32, i32), Vec<CellState>> = HashMap::new();
+        for cell in self.cells() {
+            let (p, cell) = cell?;
+            chunks
+                .entry(chunk_key(p))
+                .or_insert_with(|| vec![CellState::Empty; CHUNK_CELLS])[chunk_index(p)] = cell;
+        }
+        let mut keys: Vec<_> = chunks.keys().copied().collect();
+        keys.sort();
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        let flags = if checksum { FLAG_CHECKSUM } else { 0 };
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.push(RULE_WIREWORLD);
+        buf.extend_from_slice(&self.generation().to_le_bytes());
+        buf.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+        for key in keys {
+            let cells = &chunks[&key];
+            let rle = rle_encode(cells);
+            let (encoding, data) = if rle.len() < CHUNK_CELLS / 4 {
+                (ENCODING_RLE, rle)
+            } else {
+                (ENCODING_PACKED, pack_2bit(cells))
+            };
+            buf.extend_from_slice(&key.0.to_le_bytes());
+            buf.extend_from_slice(&key.1.to_le_bytes());
+            buf.push(encoding);
+            buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&data);
+        }
+        if checksum {
+            let crc = crc32fast::hash(&buf);
+            buf.extend_from_slice(&crc.to_le_bytes());
+        }
+        out.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub fn read_snapshot(data: &[u8]) -> Result<World> {
+        let mut r = Reader { data, pos: 0 };
+        ensure!(&r.array::<4>()? == MAGIC, "Not a snapshot: bad magic");
+        let version = r.u16()?;
+        ensure!(
+            version == VERSION,
+            "Unsupported snapshot version {}",
+            version
+        );
+        let flags = r.u16()?;
+        ensure!(
+            flags & !FLAG_CHECKSUM == 0,
+            "Unknown snapshot flags {:#x}",
+            flags
+        );
+        if flags & FLAG_CHECKSUM != 0 {
+            ensure!(data.len() >= 4, "Snapshot too short for its checksum");
+            let (body, trailer) = data.split_at(data.len() - 4);
+            let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+            let actual = crc32fast::hash(body);
+            ensure!(
+                expected == actual,
+                "Snapshot checksum mismatch: expected {:08x}, got {:08x}",
+                expected,
+                actual
+            );
+            r.data = body;
+        }
+        let rule = r.u8()?;
+        ensure!(rule == RULE_WIREWORLD, "Unsupported rule {}", rule);
+        let generation = r.u64()?;
+        let count = r.u32()? as usize;
+        ensure!(
+            count <= r.remaining() / CHUNK_HEADER,
+            "Chunk count {} is larger than the snapshot",
+            count
+        );
+
+        let mut world = World::new();
+        world.set_generation(generation);
+        let mut seen = std::collections::HashSet::new();
+        for n in 0..count {
+            let cx = r.i32()?;
+            let cy = r.i32()?;
+            let encoding = r.u8()?;
+            let len = r.u32()? as usize;
+            let data = r.take(len)?;
+            let context = || format!("In chunk {} at ({}, {})", n, cx, cy);
+            ensure!(seen.insert((cx, cy)), "Duplicate chunk ({}, {})", cx, cy);
+            let (ox, oy) = match (chunk_origin(cx), chunk_origin(cy)) {
+                (Some(ox), Some(oy)) => (ox, oy),
+                _ => bail!("Chunk ({}, {}) out of range", cx, cy),
+            };
+            let cells = match encoding {
+                ENCODING_PACKED => unpack_2bit(data, CHUNK_CELLS),
+                ENCODING_RLE => rle_decode(data, CHUNK_CELLS),
+                e => Err(anyhow!("Unknown chunk encoding {}", e)).with_context(context)?,
+            }
+            .ok_or_else(|| anyhow!("Chunk data is the wrong size"))
+            .with_context(context)?;
+            for (i, cell) in cells.into_iter().enumerate() {
+                if cell != CellState::Empty {
+                    let i = i as i32;
+                    world.set_tile(
+                        Point {
+                            x: ox + i % CHUNK_SIZE,
+                            y: oy + i / CHUNK_SIZE,
+                        },
+                        cell,
+                    )?;
+                }
+            }
+        }
+        ensure!(
+            r.remaining() == 0,
+            "{} trailing bytes after snapshot",
+            r.remaining()
+        );
+        Ok(world)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sorted_cells;
+
+    fn sample() -> World {
+        let mut world = World::new();
+        world.set_generation(1234);
+        for x in -40..40 {
+            world.set_tile(Point { x, y: 3 }, CellState::Wire).unwrap();
+        }
+        world
+            .set_tile(Point { x: -5, y: 3 }, CellState::Alive)
+            .unwrap();
+        world
+            .set_tile(Point { x: 6, y: 3 }, CellState::Dead)
+            .unwrap();
+        world
+            .set_tile(Point { x: 1000, y: -1000 }, CellState::Wire)
+            .unwrap();
+        world
+    }
+
+    fn snapshot(world: &World, checksum: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        world.write_snapshot(&mut data, checksum).unwrap();
+        data
+    }
+
+    // a snapshot without a checksum holding one chunk with a single live cell at `index`
+    fn one_chunk(cx: i32, cy: i32, index: usize) -> Vec<u8> {
+        let mut cells = vec![CellState::Empty; CHUNK_CELLS];
+        cells[index] = CellState::Wire;
+        let data = pack_2bit(&cells);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.push(RULE_WIREWORLD);
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&cx.to_le_bytes());
+        buf.extend_from_slice(&cy.to_le_bytes());
+        buf.push(ENCODING_PACKED);
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&data);
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let world = sample();
+        for checksum in [false, true] {
+            let read = World::read_snapshot(&snapshot(&world, checksum)).unwrap();
+            assert_eq!(read.generation(), 1234);
+            assert_eq!(sorted_cells(&read), sorted_cells(&world));
+        }
+        let empty = World::read_snapshot(&snapshot(&World::new(), true)).unwrap();
+        assert_eq!(empty.cells().count(), 0);
+    }
+
+    #[test]
+    fn truncated() {
+        for checksum in [false, true] {
+            let data = snapshot(&sample(), checksum);
+            for len in 0..data.len() {
+                assert!(
+                    World::read_snapshot(&data[..len]).is_err(),
+                    "length {}",
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted() {
+        let mut data = snapshot(&sample(), true);
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+        assert!(World::read_snapshot(&data).is_err());
+
+        let mut data = snapshot(&sample(), false);
+        data.push(0);
+        assert!(World::read_snapshot(&data).is_err());
+    }
+
+    #[test]
+    fn chunks_at_the_edge() {
+        let top = i32::MAX / CHUNK_SIZE;
+        let bottom = i32::MIN / CHUNK_SIZE;
+        let last = CHUNK_SIZE as usize - 1;
+        for (cx, cy, index) in [
+            (top, 0, last),
+            (0, top, CHUNK_CELLS - 1),
+            (bottom, 0, 0),
+            (0, bottom, 0),
+            (i32::MAX, 0, 0),
+        ] {
+            let err = World::read_snapshot(&one_chunk(cx, cy, index)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Chunk ({}, {}) out of range", cx, cy)
+            );
+        }
+        let world = World::read_snapshot(&one_chunk(top - 1, bottom + 1, last)).unwrap();
+        let (p, _) = world.cells().next().unwrap().unwrap();
+        assert_eq!(
+            (p.x, p.y),
+            (
+                (top - 1) * CHUNK_SIZE + last as i32,
+                (bottom + 1) * CHUNK_SIZE
+            )
+        );
+    }
+}
@@ -0,0 +1,1730 @@
+//! Talking to a client over a websocket.
+//!
+//! What's sent to a client goes through a short queue, written out by a task of its own, so
+//! that a slow connection doesn't hold up reading from it. While the queue is full, the
+//! shared world's generations are skipped for that connection, and once there's room again
+//! it's sent the generations it skipped to replay, if they were all recorded, or else the
+//! cells that changed since the last generation it was sent, rather than the whole view. A sandbox instead waits for its connection, since it's the only one looking.
+//! A client can have other views open besides the main one, each brought up to date on its
+//! own.
+//!
+//! A client has to say `Hello` first, and is turned away if it speaks another version of
+//! the protocol. It's answered in JSON if its `Hello` was, and in MessagePack otherwise.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::extract::ws::{Message, WebSocket};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use log::{debug, error};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+    task,
+    time::timeout,
+};
+use wire_universe::{
+    proto::{Encoding, ErrorCode, FromClient, FromServer, Rect, Tiles, PROTOCOL_VERSION},
+    CellState, Point, Transform,
+};
+
+use crate::{
+    host::{CellModification, Made, Scheduled, Unmade, WorldCommand, MAX_AHEAD},
+    interest::{encode, Encoded, Format, Interest, Subscription},
+    sandbox::Sandbox,
+    world::{region_fits, World},
+};
+
+/// How many generations, or batches of replies, may be waiting to be sent to a connection
+const QUEUE_LENGTH: usize = 4;
+/// The most cells one message may edit
+const MAX_EDIT_CELLS: usize = 1 << 20;
+/// The name of the view `SetView` moves
+const MAIN_VIEW: &str = "";
+/// How many views a connection can have open besides the main one
+const MAX_VIEWS: usize = 8;
+/// The longest a view's name can be, in bytes
+const MAX_VIEW_NAME: usize = 64;
+/// How many edits `At` a later generation a connection can have waiting to be made
+const MAX_PENDING: usize = 64;
+/// How many cells those edits can set between them
+const MAX_PENDING_CELLS: usize = 4 * MAX_EDIT_CELLS;
+/// How long a client has to say `Hello`
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// The optional parts of the protocol the server supports
+const CAPABILITIES: &[&str] = &[
+    "sandbox",
+    "pan",
+    "catch-up",
+    "replay",
+    "checksum",
+    "packed",
+    "run-length",
+    "views",
+];
+
+// messages that are queued together, like a generation's updates
+type Batch = Vec<Encoded>;
+
+// send everything queued, until the client goes or the connection is done with
+async fn write(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut queue: mpsc::Receiver<Batch>,
+    format: Format,
+) {
+    while let Some(batch) = queue.recv().await {
+        for msg in batch {
+            let msg = match format {
+                Format::MessagePack => Message::Binary(msg.to_vec()),
+                // already JSON, which is always UTF-8
+                Format::Json => Message::Text(String::from_utf8_lossy(&msg).into_owned()),
+            };
+            if sink.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+    _ = sink.close().await;
+}
+
+// what a message from the client says, and the format it's in, if it's meant to be read
+fn read(msg: &Message) -> Option<(Format, Result<FromClient, String>)> {
+    match msg {
+        Message::Binary(data) => Some((
+            Format::MessagePack,
+            rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+        )),
+        Message::Text(text) => Some((
+            Format::Json,
+            serde_json::from_str(text).map_err(|e| e.to_string()),
+        )),
+        _ => None,
+    }
+}
+
+// wait for the client's `Hello`, giving the format it's in and the client's capabilities,
+// or why it's turned away
+async fn handshake(stream: &mut SplitStream<WebSocket>) -> (Format, Result<Vec<String>, String>) {
+    let msg = match timeout(HELLO_TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(msg))) => read(&msg),
+        Ok(_) => None,
+        Err(_) => return (Format::MessagePack, Err("No `Hello' in time".to_owned())),
+    };
+    let Some((format, msg)) = msg else {
+        return (
+            Format::MessagePack,
+            Err("Expected `Hello' first".to_owned()),
+        );
+    };
+    let hello = match msg {
+        Ok(FromClient::Hello {
+            version,
+            capabilities,
+        }) => {
+            if version != PROTOCOL_VERSION {
+                Err(format!(
+                    "Protocol version {} isn't supported, this server speaks version {}",
+                    version, PROTOCOL_VERSION
+                ))
+            } else {
+                Ok(capabilities)
+            }
+        }
+        _ => Err("Expected `Hello' first".to_owned()),
+    };
+    (format, hello)
+}
+
+// resolves when the sandbox is due its next generation, never if there isn't one
+async fn sandbox_tick(sandbox: &mut Option<Sandbox>) {
+    match sandbox {
+        Some(sandbox) => sandbox.tick().await,
+        None => std::future::pending().await,
+    }
+}
+
+// do `f`, off the async executor if it might read cells of `world` that are paged out
+fn reading<T>(world: &World, f: impl FnOnce() -> T) -> T {
+    if world.paged_out() == 0 {
+        f()
+    } else {
+        task::block_in_place(f)
+    }
+}
+
+// `msg` as a message about the view called `name`
+fn about(name: &str, msg: FromServer) -> FromServer {
+    if name == MAIN_VIEW {
+        return msg;
+    }
+    FromServer::InView {
+        view: name.to_owned(),
+        msg: Box::new(msg),
+    }
+}
+
+// `about` for a message encoded in `format`. Updates are encoded once for everyone looking,
+// so one for a named view is wrapped as it is, without being decoded again.
+fn encoded_about(name: &str, msg: Encoded, format: Format) -> Encoded {
+    if name == MAIN_VIEW {
+        return msg;
+    }
+    let mut wrapped = Vec::with_capacity(msg.len() + name.len() + 24);
+    match format {
+        // as rmp_serde writes it, a map from the variant's name to its fields in order
+        Format::MessagePack => {
+            rmp::encode::write_map_len(&mut wrapped, 1).unwrap();
+            rmp::encode::write_str(&mut wrapped, "InView").unwrap();
+            rmp::encode::write_array_len(&mut wrapped, 2).unwrap();
+            rmp::encode::write_str(&mut wrapped, name).unwrap();
+            wrapped.extend_from_slice(&msg);
+        }
+        Format::Json => {
+            wrapped.extend_from_slice(b"{\"InView\":{\"view\":");
+            serde_json::to_writer(&mut wrapped, name).unwrap();
+            wrapped.extend_from_slice(b",\"msg\":");
+            wrapped.extend_from_slice(&msg);
+            wrapped.extend_from_slice(b"}}");
+        }
+    }
+    wrapped.into()
+}
+
+fn view_command(view: Rect) -> WorldCommand {
+    let Rect { x, y, w, h } = view;
+    WorldCommand::View { x, y, w, h }
+}
+
+// why a message was refused
+struct Refusal {
+    code: ErrorCode,
+    message: String,
+}
+
+impl Refusal {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Refusal {
+        Refusal {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// whether a message has been dealt with, or will be answered once it has
+enum Handled {
+    Now,
+    Later,
+}
+
+// the answer to a message that's been dealt with or refused, if it needs one
+fn answer(request_id: Option<u32>, result: Result<(), Refusal>) -> Option<FromServer> {
+    match result {
+        Ok(()) => request_id.map(|request_id| FromServer::Ack { request_id }),
+        Err(Refusal { code, message }) => {
+            debug!("Refusing a message: {}", message);
+            Some(FromServer::Error {
+                code,
+                message,
+                request_id,
+            })
+        }
+    }
+}
+
+// a failure reading or changing a world on the client's behalf
+fn failed(e: anyhow::Error) -> Refusal {
+    Refusal::new(ErrorCode::Invalid, format!("{:#}", e))
+}
+
+fn no_sandbox() -> Refusal {
+    Refusal::new(ErrorCode::NoSandbox, "There's no sandbox open")
+}
+
+fn check_edit_size(cells: usize) -> Result<(), Refusal> {
+    if cells > MAX_EDIT_CELLS {
+        return Err(Refusal::new(
+            ErrorCode::Invalid,
+            format!(
+                "Can't edit {} cells at once, the most is {}",
+                cells, MAX_EDIT_CELLS
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn too_late(now: u64) -> Refusal {
+    Refusal::new(
+        ErrorCode::TooLate,
+        format!("Too late, it's already generation {}", now),
+    )
+}
+
+fn too_far_ahead(generation: u64, now: u64) -> Refusal {
+    Refusal::new(
+        ErrorCode::Invalid,
+        format!(
+            "Can't edit generation {}, more than {} ahead of {}",
+            generation, MAX_AHEAD, now
+        ),
+    )
+}
+
+fn unmade(generation: u64, unmade: Unmade) -> Refusal {
+    match unmade {
+        Unmade::TooLate(now) => too_late(now),
+        Unmade::TooFarAhead(now) => too_far_ahead(generation, now),
+        Unmade::Stopped => Refusal::new(
+            ErrorCode::TooLate,
+            format!("The world stopped before generation {}", generation),
+        ),
+    }
+}
+
+// check a `w` by `h` edit at (`x`, `y`) isn't too big, and doesn't go off the edge
+fn check_edit(x: i32, y: i32, w: i32, h: i32) -> Result<(), Refusal> {
+    if w <= 0 || h <= 0 {
+        return Ok(());
+    }
+    check_edit_size(w as usize * h as usize)?;
+    if !region_fits(x, y, w, h) {
+        return Err(Refusal::new(
+            ErrorCode::Invalid,
+            "The edit goes off the edge",
+        ));
+    }
+    Ok(())
+}
+
+// the cells an edit sets
+fn modifications(edit: FromClient) -> Result<Vec<CellModification>, Refusal> {
+    match edit {
+        FromClient::ModifyCell { x, y, cell } => {
+            check_edit(x, y, 1, 1)?;
+            Ok(vec![CellModification { x, y, cell }])
+        }
+        FromClient::ModifyCells { cells } => {
+            check_edit_size(cells.len())?;
+            for &(x, y, _) in &cells {
+                check_edit(x, y, 1, 1)?;
+            }
+            Ok(cells
+                .into_iter()
+                .map(|(x, y, cell)| CellModification { x, y, cell })
+                .collect())
+        }
+        FromClient::FillRect {
+            rect: Rect { x, y, w, h },
+            cell,
+        } => {
+            check_edit(x, y, w, h)?;
+            Ok((0..h)
+                .flat_map(|dy| {
+                    (0..w).map(move |dx| CellModification {
+                        x: x + dx,
+                        y: y + dy,
+                        cell,
+                    })
+                })
+                .collect())
+        }
+        FromClient::PastePattern {
+            x,
+            y,
+            pattern,
+            transform,
+        } => paste(x, y, pattern, transform),
+        _ => Err(Refusal::new(ErrorCode::BadMessage, "Expected an edit")),
+    }
+}
+
+// the cells a pattern pasted at (`x`, `y`) sets
+fn paste(
+    x: i32,
+    y: i32,
+    pattern: Tiles,
+    transform: Transform,
+) -> Result<Vec<CellModification>, Refusal> {
+    // checked before decoding, since the size is whatever the client says it is
+    let (width, height) = pattern.size();
+    check_edit_size(width.saturating_mul(height))?;
+    let (w, h) = (width as i32, height as i32);
+    let (tw, th) = transform.size(w, h);
+    check_edit(x, y, tw, th)?;
+    let tiles = pattern
+        .decode()
+        .ok_or_else(|| Refusal::new(ErrorCode::BadMessage, "The pattern doesn't add up"))?;
+    if tiles.iter().any(|row| row.len() != width) {
+        return Err(Refusal::new(
+            ErrorCode::BadMessage,
+            "The pattern's rows aren't all as long",
+        ));
+    }
+    let mut modifications = Vec::with_capacity(w as usize * h as usize);
+    for (py, row) in tiles.into_iter().enumerate() {
+        for (px, cell) in row.into_iter().enumerate() {
+            let p = Point {
+                x: px as i32,
+                y: py as i32,
+            };
+            let Point { x: dx, y: dy } = transform.apply(p, w, h);
+            modifications.push(CellModification {
+                x: x + dx,
+                y: y + dy,
+                cell,
+            });
+        }
+    }
+    Ok(modifications)
+}
+
+// one of the rectangles a client is looking at, and what it's been sent of it
+struct View {
+    rect: Rect,
+    // the chunks being looked at, in the shared world's interest or the sandbox's
+    subscription: Subscription,
+    // what of the shared world was last queued, if the client will have it; it's brought
+    // from there to the latest generation by one `Tick` if that's the next one, or by
+    // replaying the generations in between or sending what changed over them if not
+    sent: Option<Sent>,
+    // whether the client has the sandbox's latest generation
+    synced: bool,
+}
+
+// a view as of a generation of the shared world, kept rather than the world itself so that
+// a connection that's behind holds on to no more than it's looking at
+struct Sent {
+    generation: u64,
+    tiles: Vec<Vec<CellState>>,
+}
+
+impl Sent {
+    fn of(subscription: &Subscription, world: &World) -> Sent {
+        Sent {
+            generation: world.generation(),
+            tiles: subscription.tiles(world),
+        }
+    }
+}
+
+// what one client is looking at, and what it's been sent
+struct Connection {
+    world_receiver: watch::Receiver<Arc<World>>,
+    update_sender: mpsc::UnboundedSender<WorldCommand>,
+    interest: Arc<Interest>,
+    tick: Duration,
+    encoding: Encoding,
+    format: Format,
+    // by name, with the main view as `MAIN_VIEW`
+    views: HashMap<String, View>,
+    sending: bool,
+    // while there's a sandbox, the client sees and edits it instead of the shared world
+    sandbox: Option<Sandbox>,
+    // told when edits for a later generation of the shared world are made, and how many
+    // such edits are waiting and how many cells they set
+    made: mpsc::UnboundedSender<Made>,
+    pending: usize,
+    pending_cells: usize,
+}
+
+impl Connection {
+    // what to send for the shared world's latest generation, if anything
+    fn shared_changed(&mut self, world: Arc<World>) -> Option<Batch> {
+        if !self.sending || self.sandbox.is_some() {
+            return None;
+        }
+        let mut batch = Vec::new();
+        for (name, view) in &mut self.views {
+            let subscription = &view.subscription;
+            let msgs = match &view.sent {
+                Some(sent) if world.generation() == sent.generation + 1 => {
+                    subscription.updates(&world)
+                }
+                // the generations skipped to replay if they were all recorded, and just what
+                // changed over them if not
+                Some(sent) => {
+                    let msg = subscription
+                        .replay(sent.generation, &world)
+                        .unwrap_or_else(|| subscription.catch_up(&sent.tiles, &world));
+                    vec![encode(&msg, self.format)]
+                }
+                None => vec![encode(&subscription.refresh(&world), self.format)],
+            };
+            batch.extend(
+                msgs.into_iter()
+                    .map(|msg| encoded_about(name, msg, self.format)),
+            );
+            view.sent = Some(Sent::of(subscription, &world));
+        }
+        Some(batch)
+    }
+
+    // step the sandbox, giving what to send for it if anything
+    fn sandbox_ticked(&mut self) -> Option<Batch> {
+        let sandbox = self.sandbox.as_mut()?;
+        if let Err(e) = task::block_in_place(|| sandbox.step(1)) {
+            error!("Failed to step a sandbox: {:#}", e);
+            return None;
+        }
+        if !self.sending {
+            return None;
+        }
+        let mut batch = Vec::new();
+        for (name, view) in &mut self.views {
+            let msgs = if view.synced {
+                view.subscription.updates(&sandbox.world)
+            } else {
+                vec![encode(
+                    &view.subscription.refresh(&sandbox.world),
+                    self.format,
+                )]
+            };
+            view.synced = true;
+            batch.extend(
+                msgs.into_iter()
+                    .map(|msg| encoded_about(name, msg, self.format)),
+            );
+        }
+        Some(batch)
+    }
+
+    // look at `rect` in the view called `name`, opening it if need be
+    fn set_view(&mut self, name: String, rect: Rect, replies: &mut Vec<FromServer>) {
+        let interest = match &self.sandbox {
+            Some(sandbox) => sandbox.interest.clone(),
+            None => self.interest.clone(),
+        };
+        let view = self.views.entry(name.clone()).or_insert_with(|| View {
+            rect,
+            subscription: Subscription::new(interest, self.encoding, self.format),
+            sent: None,
+            synced: false,
+        });
+        view.rect = rect;
+        let from = view.subscription.view();
+        view.subscription.set_view(rect);
+        // the client is sent the chunks newly in view as they are in the generation it has,
+        // straight away, so a paused sandbox shows them too. A view just opened is sent in
+        // full, with the shared world's next generation if there's no sandbox.
+        match &self.sandbox {
+            Some(sandbox) => {
+                if self.sending && view.synced {
+                    let pan = view.subscription.pan(from, &sandbox.world);
+                    replies.extend(pan.map(|msg| about(&name, msg)));
+                } else if self.sending {
+                    replies.push(about(&name, view.subscription.refresh(&sandbox.world)));
+                    view.synced = true;
+                }
+            }
+            None => {
+                _ = self
+                    .update_sender
+                    .send(view_command(view.subscription.view()));
+                // the chunks newly in view can only be sent as of the generation the client
+                // has while that's the latest; otherwise the view is sent in full with the
+                // next one
+                let shared = self.world_receiver.borrow().clone();
+                match &view.sent {
+                    Some(sent) if sent.generation == shared.generation() => {
+                        let pan = view.subscription.pan(from, &shared);
+                        replies.extend(pan.map(|msg| about(&name, msg)));
+                        view.sent = Some(Sent::of(&view.subscription, &shared));
+                    }
+                    _ => view.sent = None,
+                }
+            }
+        }
+    }
+
+    // look at each view in the sandbox instead, or the shared world if there isn't one,
+    // sending them in full
+    fn resubscribe(&mut self, replies: &mut Vec<FromServer>) {
+        let shared = self.world_receiver.borrow_and_update().clone();
+        let (interest, world) = match &self.sandbox {
+            Some(sandbox) => (&sandbox.interest, &sandbox.world),
+            None => (&self.interest, &*shared),
+        };
+        for (name, view) in &mut self.views {
+            view.subscription = Subscription::new(interest.clone(), self.encoding, self.format);
+            view.subscription.set_view(view.rect);
+            if self.sandbox.is_none() {
+                _ = self
+                    .update_sender
+                    .send(view_command(view.subscription.view()));
+            }
+            if self.sending {
+                replies.push(about(name, view.subscription.refresh(world)));
+            }
+            view.synced = self.sending;
+            view.sent = (self.sending && self.sandbox.is_none())
+                .then(|| Sent::of(&view.subscription, &shared));
+        }
+    }
+
+    // make several edits at once, to the sandbox if there is one
+    fn modify(&mut self, modifications: Vec<CellModification>) -> Result<(), Refusal> {
+        match &mut self.sandbox {
+            Some(sandbox) => {
+                for CellModification { x, y, cell } in modifications {
+                    sandbox.set_tile(Point { x, y }, cell).map_err(failed)?;
+                }
+            }
+            None => {
+                _ = self
+                    .update_sender
+                    .send(WorldCommand::ModifyMany(modifications));
+            }
+        }
+        Ok(())
+    }
+
+    // deal with a message from the client, sent as the request `request_id` if it was one,
+    // adding anything to send back to `replies`
+    fn handle(
+        &mut self,
+        msg: FromClient,
+        request_id: Option<u32>,
+        replies: &mut Vec<FromServer>,
+    ) -> Result<Handled, Refusal> {
+        match msg {
+            FromClient::Hello { .. } => {
+                return Err(Refusal::new(ErrorCode::BadMessage, "Already said `Hello'"));
+            }
+            FromClient::Request { .. } => {
+                return Err(Refusal::new(
+                    ErrorCode::BadMessage,
+                    "A `Request' can't be wrapped in another",
+                ));
+            }
+            FromClient::ModifyCell { x, y, cell } => {
+                check_edit(x, y, 1, 1)?;
+                match &mut self.sandbox {
+                    Some(sandbox) => sandbox.set_tile(Point { x, y }, cell).map_err(failed)?,
+                    None => {
+                        _ = self
+                            .update_sender
+                            .send(WorldCommand::Modify(CellModification { x, y, cell }));
+                    }
+                }
+            }
+            edit @ (FromClient::ModifyCells { .. }
+            | FromClient::FillRect { .. }
+            | FromClient::PastePattern { .. }) => {
+                self.modify(modifications(edit)?)?;
+            }
+            FromClient::At { generation, edit } => {
+                let modifications = modifications(*edit)?;
+                let Some(sandbox) = &self.sandbox else {
+                    let now = self.world_receiver.borrow().generation();
+                    if generation > now.saturating_add(MAX_AHEAD) {
+                        return Err(too_far_ahead(generation, now));
+                    }
+                    if self.pending == MAX_PENDING
+                        || self.pending_cells + modifications.len() > MAX_PENDING_CELLS
+                    {
+                        return Err(Refusal::new(
+                            ErrorCode::Invalid,
+                            format!(
+                                "Too many edits waiting, the most is {} setting {} cells",
+                                MAX_PENDING, MAX_PENDING_CELLS
+                            ),
+                        ));
+                    }
+                    self.pending += 1;
+                    self.pending_cells += modifications.len();
+                    let scheduled =
+                        Scheduled::new(generation, modifications, request_id, self.made.clone());
+                    _ = self.update_sender.send(WorldCommand::ModifyAt(scheduled));
+                    return Ok(Handled::Later);
+                };
+                let now = sandbox.world.generation();
+                if generation < now {
+                    return Err(too_late(now));
+                }
+                if generation > now {
+                    return Err(Refusal::new(
+                        ErrorCode::Invalid,
+                        "A sandbox can't be edited ahead of time, pause it and `Step' instead",
+                    ));
+                }
+                self.modify(modifications)?;
+            }
+            FromClient::SetView { x, y, w, h } => {
+                self.set_view(MAIN_VIEW.to_owned(), Rect { x, y, w, h }, replies);
+            }
+            FromClient::SetNamedView { name, x, y, w, h } => {
+                if name.is_empty() || name.len() > MAX_VIEW_NAME {
+                    return Err(Refusal::new(
+                        ErrorCode::Invalid,
+                        format!("A view's name has to be 1 to {} bytes", MAX_VIEW_NAME),
+                    ));
+                }
+                // counting the main view
+                if !self.views.contains_key(&name) && self.views.len() > MAX_VIEWS {
+                    return Err(Refusal::new(
+                        ErrorCode::Invalid,
+                        format!("Can't have more than {} other views open", MAX_VIEWS),
+                    ));
+                }
+                self.set_view(name, Rect { x, y, w, h }, replies);
+            }
+            FromClient::CloseView { name } => {
+                if name == MAIN_VIEW {
+                    return Err(Refusal::new(
+                        ErrorCode::Invalid,
+                        "The main view can't be closed",
+                    ));
+                }
+                self.views.remove(&name).ok_or_else(|| {
+                    Refusal::new(ErrorCode::Invalid, format!("No view called `{}'", name))
+                })?;
+            }
+            FromClient::StartStream => {
+                match &self.sandbox {
+                    Some(sandbox) => {
+                        for (name, view) in &mut self.views {
+                            replies.push(about(name, view.subscription.refresh(&sandbox.world)));
+                            view.synced = true;
+                        }
+                    }
+                    None => {
+                        let world = self.world_receiver.borrow_and_update().clone();
+                        for (name, view) in &mut self.views {
+                            _ = self
+                                .update_sender
+                                .send(view_command(view.subscription.view()));
+                            replies.push(about(name, view.subscription.refresh(&world)));
+                            view.sent = Some(Sent::of(&view.subscription, &world));
+                        }
+                    }
+                }
+                self.sending = true;
+            }
+            // refreshed as of the generation the client has, so what's already queued still
+            // follows on from it
+            FromClient::Resync => {
+                for (name, view) in &mut self.views {
+                    match &self.sandbox {
+                        Some(sandbox) => {
+                            if self.sending {
+                                replies
+                                    .push(about(name, view.subscription.refresh(&sandbox.world)));
+                                view.synced = true;
+                            }
+                        }
+                        None => {
+                            if let Some(sent) = &view.sent {
+                                debug!("Resyncing a client at generation {}", sent.generation);
+                                let tiles = sent.tiles.clone();
+                                let msg = view.subscription.refresh_from(sent.generation, tiles);
+                                replies.push(about(name, msg));
+                            }
+                        }
+                    }
+                }
+            }
+            FromClient::Fork { region } => {
+                let shared = self.world_receiver.borrow().clone();
+                let forked = task::block_in_place(|| Sandbox::fork(&shared, region, self.tick))
+                    .map_err(failed)?;
+                replies.push(forked.status());
+                self.sandbox = Some(forked);
+                self.resubscribe(replies);
+            }
+            FromClient::Pause => {
+                let sandbox = self.sandbox.as_mut().ok_or_else(no_sandbox)?;
+                sandbox.pause();
+                replies.push(sandbox.status());
+            }
+            FromClient::Resume => {
+                let sandbox = self.sandbox.as_mut().ok_or_else(no_sandbox)?;
+                sandbox.resume();
+                replies.push(sandbox.status());
+            }
+            FromClient::Step { generations } => {
+                let sandbox = self.sandbox.as_mut().ok_or_else(no_sandbox)?;
+                let from = sandbox.world.generation();
+                task::block_in_place(|| sandbox.step(generations)).map_err(failed)?;
+                replies.push(sandbox.status());
+                if self.sending {
+                    for (name, view) in &mut self.views {
+                        let replay = view
+                            .synced
+                            .then(|| view.subscription.replay(from, &sandbox.world))
+                            .flatten();
+                        let msg =
+                            replay.unwrap_or_else(|| view.subscription.refresh(&sandbox.world));
+                        replies.push(about(name, msg));
+                        view.synced = true;
+                    }
+                }
+            }
+            FromClient::SetSpeed { tick_ms } => {
+                let sandbox = self.sandbox.as_mut().ok_or_else(no_sandbox)?;
+                sandbox.set_speed(Duration::from_millis(tick_ms));
+                replies.push(sandbox.status());
+            }
+            FromClient::Merge | FromClient::LeaveSandbox => {
+                let closed = self.sandbox.take().ok_or_else(no_sandbox)?;
+                let merge = msg == FromClient::Merge;
+                if merge {
+                    _ = self
+                        .update_sender
+                        .send(WorldCommand::ModifyMany(closed.into_edits()));
+                }
+                replies.push(FromServer::SandboxClosed);
+                self.resubscribe(replies);
+                // merged edits only show up in the next generation, so that has to be sent
+                // in full
+                if merge {
+                    for view in self.views.values_mut() {
+                        view.sent = None;
+                    }
+                }
+            }
+        }
+        Ok(Handled::Now)
+    }
+}
+
+pub(crate) async fn handle_socket(
+    socket: WebSocket,
+    name: String,
+    world_receiver: watch::Receiver<Arc<World>>,
+    update_sender: mpsc::UnboundedSender<WorldCommand>,
+    interest: Arc<Interest>,
+    tick: Duration,
+) {
+    let (sink, mut stream) = socket.split();
+    let (format, hello) = handshake(&mut stream).await;
+    let (queue, queued) = mpsc::channel(QUEUE_LENGTH);
+    task::spawn(write(sink, queued, format));
+    let capabilities = match hello {
+        Ok(capabilities) => capabilities,
+        Err(reason) => {
+            debug!("Turning away a client: {}", reason);
+            _ = queue
+                .send(vec![encode(&FromServer::Rejected { reason }, format)])
+                .await;
+            return;
+        }
+    };
+    let encoding = Encoding::choose(&capabilities);
+    let welcome = {
+        let world = world_receiver.borrow().clone();
+        FromServer::Welcome {
+            version: PROTOCOL_VERSION,
+            rule: "wireworld".to_owned(),
+            tick_ms: tick.as_millis() as u64,
+            generation: world.generation(),
+            world: name,
+            bounds: world.bounds().map(|(x, y, w, h)| Rect { x, y, w, h }),
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    };
+    if queue.send(vec![encode(&welcome, format)]).await.is_err() {
+        return;
+    }
+    let view = Rect {
+        x: 0,
+        y: 0,
+        w: 30,
+        h: 30,
+    };
+    let (made, mut made_receiver) = mpsc::unbounded_channel();
+    let mut subscription = Subscription::new(interest.clone(), encoding, format);
+    subscription.set_view(view);
+    let main = View {
+        rect: view,
+        subscription,
+        sent: None,
+        synced: false,
+    };
+    let mut conn = Connection {
+        world_receiver,
+        update_sender,
+        interest,
+        tick,
+        encoding,
+        format,
+        views: HashMap::from([(MAIN_VIEW.to_owned(), main)]),
+        sending: false,
+        sandbox: None,
+        made,
+        pending: 0,
+        pending_cells: 0,
+    };
+    loop {
+        let batch = select! {
+            changed = conn.world_receiver.changed() => {
+                // the world was deleted
+                if changed.is_err() {
+                    return;
+                }
+                let world = conn.world_receiver.borrow_and_update().clone();
+                // skip generations while the client is behind
+                if queue.capacity() == 0 {
+                    continue;
+                }
+                reading(&world.clone(), || conn.shared_changed(world))
+            }
+            _ = sandbox_tick(&mut conn.sandbox) => {
+                // the sandbox waits for the client to catch up
+                if conn.sending && queue.capacity() == 0 {
+                    continue;
+                }
+                conn.sandbox_ticked()
+            }
+            Some(made) = made_receiver.recv() => {
+                conn.pending -= 1;
+                conn.pending_cells -= made.cells;
+                let result = made.result.map_err(|e| unmade(made.generation, e));
+                answer(made.request_id, result).map(|reply| vec![encode(&reply, conn.format)])
+            }
+            msg = stream.next() => {
+                // the client went
+                let Some(msg) = msg else {
+                    return;
+                };
+                let Some((_, msg)) = msg.ok().as_ref().and_then(read) else {
+                    continue;
+                };
+                let mut replies = Vec::new();
+                let shared = conn.world_receiver.borrow().clone();
+                let (request_id, result) = reading(&shared, || match msg {
+                    Ok(FromClient::Request { id, request }) => {
+                        (Some(id), conn.handle(*request, Some(id), &mut replies))
+                    }
+                    Ok(msg) => (None, conn.handle(msg, None, &mut replies)),
+                    Err(e) => (None, Err(Refusal::new(ErrorCode::BadMessage, e))),
+                });
+                match result {
+                    Ok(Handled::Later) => {}
+                    result => replies.extend(answer(request_id, result.map(|_| ()))),
+                }
+                let encoded = replies.iter().map(|reply| encode(reply, conn.format));
+                (!replies.is_empty()).then(|| encoded.collect())
+            }
+        };
+        if let Some(batch) = batch {
+            if queue.send(batch).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::WebSocketUpgrade, routing::get, Router};
+    use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+    use wire_universe::{
+        proto::{Bytes, CHUNK_SIZE},
+        Rotation,
+    };
+
+    use super::*;
+
+    type Client = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    // a server for an empty world, giving where to connect to and the world's channels, which
+    // have to outlive connections to it
+    async fn serve() -> (
+        String,
+        watch::Sender<Arc<World>>,
+        mpsc::UnboundedReceiver<WorldCommand>,
+    ) {
+        let (world_sender, world_receiver) = watch::channel(Arc::new(World::new()));
+        let (update_sender, update_receiver) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/ws",
+            get(move |ws: WebSocketUpgrade| async move {
+                ws.on_upgrade(move |socket| {
+                    handle_socket(
+                        socket,
+                        "test".to_owned(),
+                        world_receiver,
+                        update_sender,
+                        Arc::new(Interest::new()),
+                        Duration::from_millis(100),
+                    )
+                })
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        task::spawn(server);
+        (format!("ws://{}/ws", addr), world_sender, update_receiver)
+    }
+
+    // a connection to an empty world that's said `Hello`, and the world's channels
+    async fn connect() -> (
+        Client,
+        watch::Sender<Arc<World>>,
+        mpsc::UnboundedReceiver<WorldCommand>,
+    ) {
+        let (url, world_sender, update_receiver) = serve().await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+        send(&mut client, hello(PROTOCOL_VERSION)).await;
+        (client, world_sender, update_receiver)
+    }
+
+    fn hello(version: u32) -> FromClient {
+        FromClient::Hello {
+            version,
+            capabilities: Vec::new(),
+        }
+    }
+
+    async fn send(client: &mut Client, msg: FromClient) {
+        let data = rmp_serde::to_vec(&msg).unwrap();
+        client
+            .send(tungstenite::Message::Binary(data))
+            .await
+            .unwrap();
+    }
+
+    // the answer to request `id`, skipping anything else
+    async fn answer_to(client: &mut Client, id: u32) -> FromServer {
+        loop {
+            let msg = timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("no answer")
+                .expect("the connection closed")
+                .unwrap();
+            let tungstenite::Message::Binary(data) = msg else {
+                continue;
+            };
+            match rmp_serde::from_slice(&data).unwrap() {
+                msg @ (FromServer::Ack { request_id }
+                | FromServer::Error {
+                    request_id: Some(request_id),
+                    ..
+                }) if request_id == id => return msg,
+                _ => {}
+            }
+        }
+    }
+
+    fn request(id: u32, msg: FromClient) -> FromClient {
+        FromClient::Request {
+            id,
+            request: Box::new(msg),
+        }
+    }
+
+    fn paste(x: i32, y: i32, pattern: Tiles) -> FromClient {
+        FromClient::PastePattern {
+            x,
+            y,
+            pattern,
+            transform: Transform::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_pattern() {
+        let (mut client, _world, _updates) = connect().await;
+        let huge = Tiles::RunLength {
+            w: 1 << 20,
+            h: 1 << 20,
+            data: Bytes(vec![0xff; 4]),
+        };
+        let small = Tiles::encode(vec![vec![CellState::Wire; 4]; 4], Encoding::RunLength);
+        let edits = [
+            paste(0, 0, huge),
+            paste(i32::MAX - 2, 0, small.clone()),
+            paste(0, i32::MIN, small.clone()),
+            FromClient::ModifyCell {
+                x: i32::MAX,
+                y: 0,
+                cell: CellState::Wire,
+            },
+        ];
+        for (id, edit) in edits.into_iter().enumerate() {
+            let id = id as u32;
+            send(&mut client, request(id, edit)).await;
+            let answer = answer_to(&mut client, id).await;
+            assert!(
+                matches!(
+                    answer,
+                    FromServer::Error {
+                        code: ErrorCode::Invalid,
+                        ..
+                    }
+                ),
+                "{:?}",
+                answer
+            );
+        }
+        send(&mut client, request(10, paste(5, 5, small))).await;
+        assert_eq!(
+            answer_to(&mut client, 10).await,
+            FromServer::Ack { request_id: 10 }
+        );
+    }
+
+    fn at(generation: u64, x: i32) -> FromClient {
+        FromClient::At {
+            generation,
+            edit: Box::new(FromClient::ModifyCell {
+                x,
+                y: 0,
+                cell: CellState::Wire,
+            }),
+        }
+    }
+
+    fn code(msg: FromServer) -> Option<ErrorCode> {
+        match msg {
+            FromServer::Error { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_edits() {
+        let (mut client, _world, updates) = connect().await;
+        send(&mut client, request(0, at(MAX_AHEAD + 1, 0))).await;
+        assert_eq!(
+            code(answer_to(&mut client, 0).await),
+            Some(ErrorCode::Invalid)
+        );
+
+        // nothing steps the world, so these wait
+        for id in 1..=MAX_PENDING as u32 {
+            send(&mut client, request(id, at(5, id as i32))).await;
+        }
+        let id = MAX_PENDING as u32 + 1;
+        send(&mut client, request(id, at(5, 0))).await;
+        assert_eq!(
+            code(answer_to(&mut client, id).await),
+            Some(ErrorCode::Invalid)
+        );
+
+        // as if the world stopped
+        drop(updates);
+        for id in 1..=MAX_PENDING as u32 {
+            assert_eq!(
+                code(answer_to(&mut client, id).await),
+                Some(ErrorCode::TooLate)
+            );
+        }
+        // and there's room again
+        send(&mut client, request(100, at(5, 0))).await;
+        assert_eq!(
+            code(answer_to(&mut client, 100).await),
+            Some(ErrorCode::TooLate)
+        );
+    }
+
+    // the edits sent to the shared world so far, leaving out what's being looked at
+    fn edits(updates: &mut mpsc::UnboundedReceiver<WorldCommand>) -> Vec<(i32, i32, CellState)> {
+        let mut edits = Vec::new();
+        while let Ok(command) = updates.try_recv() {
+            match command {
+                WorldCommand::Modify(edit) => edits.push((edit.x, edit.y, edit.cell)),
+                WorldCommand::ModifyMany(many) => {
+                    edits.extend(many.into_iter().map(|edit| (edit.x, edit.y, edit.cell)))
+                }
+                WorldCommand::ModifyAt(_) => panic!("scheduled an edit"),
+                WorldCommand::View { .. } => {}
+            }
+        }
+        edits
+    }
+
+    // sandboxes step off the async executor, which needs more than one thread
+    #[tokio::test(flavor = "multi_thread")]
+    async fn merging_a_sandbox() {
+        let (mut client, _world, mut updates) = connect().await;
+        let edit = |x| FromClient::ModifyCell {
+            x,
+            y: 2,
+            cell: CellState::Wire,
+        };
+        for (id, msg) in [
+            FromClient::Fork { region: None },
+            edit(3),
+            edit(4),
+            FromClient::ModifyCell {
+                x: 4,
+                y: 2,
+                cell: CellState::Empty,
+            },
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let id = id as u32;
+            send(&mut client, request(id, msg)).await;
+            assert_eq!(
+                answer_to(&mut client, id).await,
+                FromServer::Ack { request_id: id }
+            );
+        }
+        // nothing reaches the shared world until the sandbox is merged
+        assert_eq!(edits(&mut updates), []);
+        send(&mut client, request(10, FromClient::Merge)).await;
+        assert_eq!(
+            answer_to(&mut client, 10).await,
+            FromServer::Ack { request_id: 10 }
+        );
+        let mut merged = edits(&mut updates);
+        merged.sort_by_key(|&(x, y, _)| (x, y));
+        assert_eq!(merged, [(3, 2, CellState::Wire), (4, 2, CellState::Empty)]);
+
+        // and leaving without merging throws the edits away
+        for (id, msg) in [
+            FromClient::Fork { region: None },
+            edit(5),
+            FromClient::LeaveSandbox,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let id = 20 + id as u32;
+            send(&mut client, request(id, msg)).await;
+            assert_eq!(
+                answer_to(&mut client, id).await,
+                FromServer::Ack { request_id: id }
+            );
+        }
+        assert_eq!(edits(&mut updates), []);
+        // edits go to the shared world again
+        send(&mut client, request(30, edit(6))).await;
+        assert_eq!(
+            answer_to(&mut client, 30).await,
+            FromServer::Ack { request_id: 30 }
+        );
+        assert_eq!(edits(&mut updates), [(6, 2, CellState::Wire)]);
+    }
+
+    // the next message, if one comes before long
+    async fn next(client: &mut Client, wait: Duration) -> Option<FromServer> {
+        loop {
+            let msg = timeout(wait, client.next()).await.ok()?;
+            if let tungstenite::Message::Binary(data) = msg.unwrap().unwrap() {
+                return Some(rmp_serde::from_slice(&data).unwrap());
+            }
+        }
+    }
+
+    // what the client sees of `tiles`, from a message about the main view, giving the
+    // generation it brings it to
+    fn apply(tiles: &mut [Vec<CellState>], msg: FromServer) -> Option<u64> {
+        let mut set = |x: i32, y: i32, cell| tiles[y as usize][x as usize] = cell;
+        match msg {
+            FromServer::Chunk {
+                x, y, tiles: chunk, ..
+            } => {
+                for (i, cell) in chunk.into_iter().enumerate() {
+                    let i = i as i32;
+                    set(x + i % CHUNK_SIZE, y + i / CHUNK_SIZE, cell);
+                }
+                None
+            }
+            FromServer::FullRefresh {
+                generation,
+                x,
+                y,
+                tiles: refresh,
+            } => {
+                for (dy, row) in refresh.decode().unwrap().into_iter().enumerate() {
+                    for (dx, cell) in row.into_iter().enumerate() {
+                        set(x + dx as i32, y + dy as i32, cell);
+                    }
+                }
+                Some(generation)
+            }
+            FromServer::CatchUp {
+                generation,
+                x,
+                y,
+                cells,
+            } => {
+                for (dx, dy, cell) in cells {
+                    set(x + dx as i32, y + dy as i32, cell);
+                }
+                Some(generation)
+            }
+            FromServer::Tick { generation } => Some(generation),
+            msg => panic!("unexpected {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn falling_behind() {
+        let (mut client, world_sender, _updates) = connect().await;
+        // a view big enough that a few generations fill up everything between the server
+        // and the client
+        const SIZE: i32 = 1024;
+        send(
+            &mut client,
+            request(
+                0,
+                FromClient::SetView {
+                    x: 0,
+                    y: 0,
+                    w: SIZE,
+                    h: SIZE,
+                },
+            ),
+        )
+        .await;
+        answer_to(&mut client, 0).await;
+        send(&mut client, FromClient::StartStream).await;
+
+        // nothing records the generations, so every chunk in view is sent in full each time,
+        // and there's nothing to replay
+        let mut world = World::new();
+        let generations = 30;
+        for generation in 1..=generations {
+            world.set_generation(generation);
+            let x = generation as i32;
+            world.set_tile(Point { x, y: 1 }, CellState::Wire).unwrap();
+            world
+                .set_tile(Point { x: x - 2, y: 1 }, CellState::Empty)
+                .unwrap();
+            world_sender.send_replace(Arc::new(world.clone()));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let mut tiles = vec![vec![CellState::Empty; SIZE as usize]; SIZE as usize];
+        let mut ticks = 0;
+        while let Some(msg) = next(&mut client, Duration::from_millis(500)).await {
+            ticks += matches!(msg, FromServer::Tick { .. }) as u64;
+            apply(&mut tiles, msg);
+        }
+        // the generations that didn't fit in the queue were skipped
+        assert!(ticks < generations, "{} generations sent", ticks);
+
+        // and once there's room, the next is sent as what changed since the last one queued
+        world.set_generation(generations + 1);
+        world
+            .set_tile(Point { x: 0, y: 5 }, CellState::Alive)
+            .unwrap();
+        world_sender.send_replace(Arc::new(world.clone()));
+        let msg = next(&mut client, Duration::from_secs(5)).await.unwrap();
+        assert!(matches!(msg, FromServer::CatchUp { .. }), "{:?}", msg);
+        assert_eq!(apply(&mut tiles, msg), Some(generations + 1));
+        assert_eq!(tiles, world.copy_slice(0, 0, SIZE, SIZE));
+    }
+
+    // a client whose view doesn't match the checksum asks to resync, and is sent the view as
+    // of the generation it has; it can also move its view and be sent just what's new
+    #[tokio::test]
+    async fn resyncing_and_panning() {
+        let (mut client, world_sender, _updates) = connect().await;
+        send(&mut client, FromClient::StartStream).await;
+        let wait = Duration::from_secs(5);
+        let refreshed = |msg: Option<FromServer>| {
+            matches!(msg.expect("no refresh"), FromServer::FullRefresh { .. })
+        };
+        while !refreshed(next(&mut client, wait).await) {}
+        let mut world = World::new();
+        world.set_generation(1);
+        world
+            .set_tile(Point { x: 3, y: 3 }, CellState::Wire)
+            .unwrap();
+        world
+            .set_tile(Point { x: 40, y: 3 }, CellState::Wire)
+            .unwrap();
+        world_sender.send_replace(Arc::new(world.clone()));
+        let tick = FromServer::Tick { generation: 1 };
+        while next(&mut client, wait).await.expect("no tick") != tick {}
+
+        send(&mut client, FromClient::Resync).await;
+        let Some(FromServer::FullRefresh {
+            generation,
+            x,
+            y,
+            tiles,
+        }) = next(&mut client, wait).await
+        else {
+            panic!("expected a refresh");
+        };
+        assert_eq!((generation, x, y), (1, 0, 0));
+        assert_eq!(
+            tiles.decode().unwrap(),
+            world.copy_slice(0, 0, CHUNK_SIZE, CHUNK_SIZE)
+        );
+
+        let view = FromClient::SetView {
+            x: 10,
+            y: 0,
+            w: 40,
+            h: 10,
+        };
+        send(&mut client, view).await;
+        let Some(FromServer::Pan {
+            generation, chunks, ..
+        }) = next(&mut client, wait).await
+        else {
+            panic!("expected a pan");
+        };
+        assert_eq!(generation, 1);
+        let [(x, y, tiles)] = &chunks[..] else {
+            panic!("{} chunks", chunks.len());
+        };
+        assert_eq!((*x, *y), (CHUNK_SIZE, 0));
+        assert_eq!(
+            tiles.clone().decode().unwrap(),
+            world.copy_slice(CHUNK_SIZE, 0, CHUNK_SIZE, CHUNK_SIZE)
+        );
+        // and resyncing now sends the new view
+        send(&mut client, FromClient::Resync).await;
+        let Some(FromServer::FullRefresh {
+            generation, tiles, ..
+        }) = next(&mut client, wait).await
+        else {
+            panic!("expected a refresh");
+        };
+        assert_eq!(generation, 1);
+        assert_eq!(
+            tiles.decode().unwrap(),
+            world.copy_slice(0, 0, 2 * CHUNK_SIZE, CHUNK_SIZE)
+        );
+    }
+
+    // why a client that didn't start with a usable `Hello' was turned away
+    async fn turned_away(first: Option<FromClient>) -> String {
+        let (url, _world, _updates) = serve().await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+        if let Some(msg) = first {
+            send(&mut client, msg).await;
+        }
+        let mut reason = None;
+        // the connection is closed afterwards
+        while let Some(msg) = client.next().await {
+            match msg.unwrap() {
+                tungstenite::Message::Binary(data) => match rmp_serde::from_slice(&data).unwrap() {
+                    FromServer::Rejected { reason: why } => reason = Some(why),
+                    msg => panic!("unexpected {:?}", msg),
+                },
+                tungstenite::Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        reason.expect("not turned away")
+    }
+
+    #[tokio::test]
+    async fn handshakes() {
+        let reason = turned_away(Some(hello(PROTOCOL_VERSION + 1))).await;
+        assert!(reason.contains("isn't supported"), "{}", reason);
+        let reason = turned_away(Some(FromClient::StartStream)).await;
+        assert_eq!(reason, "Expected `Hello' first");
+
+        // and a client saying the right thing is welcomed
+        let (mut client, _world, _updates) = connect().await;
+        let welcome = timeout(Duration::from_secs(5), client.next())
+            .await
+            .unwrap();
+        let tungstenite::Message::Binary(data) = welcome.unwrap().unwrap() else {
+            panic!("expected a binary frame");
+        };
+        let welcome: FromServer = rmp_serde::from_slice(&data).unwrap();
+        assert!(matches!(
+            welcome,
+            FromServer::Welcome {
+                version: PROTOCOL_VERSION,
+                ..
+            }
+        ));
+    }
+
+    // time only moves on when there's nothing else to do, so the client has said nothing by
+    // the time it's up
+    #[tokio::test(start_paused = true)]
+    async fn slow_hello() {
+        assert_eq!(turned_away(None).await, "No `Hello' in time");
+    }
+
+    #[test]
+    fn answering() {
+        assert_eq!(answer(None, Ok(())), None);
+        assert_eq!(
+            answer(Some(3), Ok(())),
+            Some(FromServer::Ack { request_id: 3 })
+        );
+        for request_id in [None, Some(4)] {
+            let refused = Err(Refusal::new(ErrorCode::Invalid, "no"));
+            assert_eq!(
+                answer(request_id, refused),
+                Some(FromServer::Error {
+                    code: ErrorCode::Invalid,
+                    message: "no".to_owned(),
+                    request_id,
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered() {
+        let (mut client, _world, _updates) = connect().await;
+        let edit = FromClient::ModifyCell {
+            x: 1,
+            y: 1,
+            cell: CellState::Wire,
+        };
+        send(&mut client, request(7, edit)).await;
+        assert_eq!(
+            answer_to(&mut client, 7).await,
+            FromServer::Ack { request_id: 7 }
+        );
+
+        let close = || FromClient::CloseView {
+            name: "nowhere".to_owned(),
+        };
+        send(&mut client, request(8, close())).await;
+        let FromServer::Error { code, message, .. } = answer_to(&mut client, 8).await else {
+            panic!("expected an error");
+        };
+        assert_eq!(code, ErrorCode::Invalid);
+        assert_eq!(message, "No view called `nowhere'");
+
+        // a refused message that wasn't a request is still answered, just without an id,
+        // as is one that can't be read
+        send(&mut client, close()).await;
+        let garbage = tungstenite::Message::Binary(vec![0xc1]);
+        client.send(garbage).await.unwrap();
+        let mut codes = Vec::new();
+        while codes.len() < 2 {
+            let msg = next(&mut client, Duration::from_secs(5))
+                .await
+                .expect("no error");
+            if let FromServer::Error {
+                code,
+                request_id: None,
+                ..
+            } = msg
+            {
+                codes.push(code);
+            }
+        }
+        assert_eq!(codes, [ErrorCode::Invalid, ErrorCode::BadMessage]);
+    }
+
+    #[tokio::test]
+    async fn json_clients() {
+        let (url, _world, _updates) = serve().await;
+        let (mut client, _) = connect_async(url).await.unwrap();
+        for msg in [hello(PROTOCOL_VERSION), request(1, FromClient::StartStream)] {
+            let text = serde_json::to_string(&msg).unwrap();
+            client.send(tungstenite::Message::Text(text)).await.unwrap();
+        }
+        // answered in text frames, every one of them JSON
+        let mut answers = Vec::new();
+        while answers.len() < 3 {
+            let msg = timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("no answer")
+                .unwrap()
+                .unwrap();
+            let tungstenite::Message::Text(text) = msg else {
+                panic!("expected a text frame, got {:?}", msg);
+            };
+            answers.push(serde_json::from_str::<FromServer>(&text).unwrap());
+        }
+        assert!(
+            matches!(answers[0], FromServer::Welcome { .. }),
+            "{:?}",
+            answers
+        );
+        assert!(
+            matches!(answers[1], FromServer::FullRefresh { generation: 0, .. }),
+            "{:?}",
+            answers
+        );
+        assert_eq!(answers[2], FromServer::Ack { request_id: 1 });
+    }
+
+    // what an edit sets, in order of position
+    fn sets(edit: FromClient) -> Vec<(i32, i32, CellState)> {
+        let Ok(modifications) = modifications(edit) else {
+            panic!("refused");
+        };
+        let mut cells: Vec<_> = modifications
+            .into_iter()
+            .map(|CellModification { x, y, cell }| (x, y, cell))
+            .collect();
+        cells.sort_by_key(|&(x, y, _)| (y, x));
+        cells
+    }
+
+    #[test]
+    fn edits_land_where_they_should() {
+        use CellState::{Alive as A, Dead as D, Empty as E, Wire as W};
+        let cells = vec![(5, -2, W), (-1, 3, A)];
+        assert_eq!(
+            sets(FromClient::ModifyCells {
+                cells: cells.clone()
+            }),
+            [(5, -2, W), (-1, 3, A)]
+        );
+        let rect = Rect {
+            x: -1,
+            y: 4,
+            w: 3,
+            h: 2,
+        };
+        assert_eq!(
+            sets(FromClient::FillRect { rect, cell: D }),
+            [
+                (-1, 4, D),
+                (0, 4, D),
+                (1, 4, D),
+                (-1, 5, D),
+                (0, 5, D),
+                (1, 5, D)
+            ]
+        );
+
+        // a pattern three wide and two high:
+        //   W A E
+        //   D E E
+        let pattern = Tiles::encode(vec![vec![W, A, E], vec![D, E, E]], Encoding::Plain);
+        let pasted = |rotation, mirror| {
+            let transform = Transform { mirror, rotation };
+            sets(FromClient::PastePattern {
+                x: 10,
+                y: 20,
+                pattern: pattern.clone(),
+                transform,
+            })
+            .into_iter()
+            .filter(|&(_, _, cell)| cell != E)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            pasted(Rotation::R0, false),
+            [(10, 20, W), (11, 20, A), (10, 21, D)]
+        );
+        // turned clockwise, it's two wide and three high:
+        //   D W
+        //   E A
+        //   E E
+        assert_eq!(
+            pasted(Rotation::R90, false),
+            [(10, 20, D), (11, 20, W), (11, 21, A)]
+        );
+        assert_eq!(
+            pasted(Rotation::R180, false),
+            [(12, 20, D), (11, 21, A), (12, 21, W)]
+        );
+        // mirrored:
+        //   E A W
+        //   E E D
+        assert_eq!(
+            pasted(Rotation::R0, true),
+            [(11, 20, A), (12, 20, W), (12, 21, D)]
+        );
+        // empty cells are pasted too
+        assert_eq!(sets(paste(0, 0, pattern.clone())).len(), 6);
+        // it has to fit where it goes as it ends up
+        let at_edge = |rotation| FromClient::PastePattern {
+            x: 0,
+            y: i32::MAX - 2,
+            pattern: pattern.clone(),
+            transform: Transform {
+                mirror: false,
+                rotation,
+            },
+        };
+        assert!(modifications(at_edge(Rotation::R0)).is_ok());
+        assert!(modifications(at_edge(Rotation::R90)).is_err());
+    }
+
+    #[test]
+    fn messages_about_views() {
+        let msgs = || {
+            let refresh = FromServer::FullRefresh {
+                generation: 7,
+                x: -32,
+                y: 0,
+                tiles: Tiles::encode(vec![vec![CellState::Wire; 3]; 2], Encoding::RunLength),
+            };
+            [refresh, FromServer::Tick { generation: 7 }]
+        };
+        for format in [Format::MessagePack, Format::Json] {
+            let tick = FromServer::Tick { generation: 7 };
+            let encoded = encoded_about(MAIN_VIEW, encode(&tick, format), format);
+            assert_eq!(&encoded[..], &encode(&tick, format)[..]);
+            for msg in msgs() {
+                let encoded = encoded_about("side \"view\"", encode(&msg, format), format);
+                let expected = encode(&about("side \"view\"", msg), format);
+                assert_eq!(&encoded[..], &expected[..]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn two_named_views() {
+        let (mut client, world_sender, _updates) = connect().await;
+        let views = [("left", 0), ("right", 4 * CHUNK_SIZE)];
+        for (name, x) in views {
+            let view = FromClient::SetNamedView {
+                name: name.to_owned(),
+                x,
+                y: 0,
+                w: 10,
+                h: 10,
+            };
+            send(&mut client, view).await;
+        }
+        send(&mut client, FromClient::StartStream).await;
+        let mut world = World::new();
+        world.set_generation(1);
+        for (_, x) in views {
+            world
+                .set_tile(Point { x: x + 3, y: 3 }, CellState::Wire)
+                .unwrap();
+        }
+        let wait = Duration::from_secs(5);
+        let mut seen: HashMap<String, Vec<FromServer>> = HashMap::new();
+        let ticked = |seen: &HashMap<String, Vec<FromServer>>, name| {
+            seen.get(name)
+                .is_some_and(|msgs| msgs.contains(&FromServer::Tick { generation: 1 }))
+        };
+        while !views.iter().all(|&(name, _)| ticked(&seen, name)) {
+            let (view, msg) = match next(&mut client, wait).await.expect("no tick") {
+                FromServer::InView { view, msg } => (view, *msg),
+                msg => (MAIN_VIEW.to_owned(), msg),
+            };
+            // published once both views are refreshed, so the updates follow on
+            if matches!(msg, FromServer::FullRefresh { .. }) {
+                let refreshed = seen.keys().filter(|name| *name != MAIN_VIEW).count();
+                if view != MAIN_VIEW && refreshed == 1 {
+                    world_sender.send_replace(Arc::new(world.clone()));
+                }
+            }
+            seen.entry(view).or_default().push(msg);
+        }
+        for (name, x) in views {
+            let msgs = &seen[name];
+            assert!(matches!(
+                msgs[0],
+                FromServer::FullRefresh { generation: 0, x: rx, y: 0, .. } if rx == x
+            ));
+            let chunk = msgs.iter().find_map(|msg| match msg {
+                FromServer::Chunk {
+                    generation: 1,
+                    x,
+                    y,
+                    tiles,
+                } => Some((*x, *y, tiles)),
+                _ => None,
+            });
+            let (cx, cy, tiles) = chunk.expect("no chunk");
+            assert_eq!((cx, cy), (x, 0));
+            assert_eq!(tiles[(3 * CHUNK_SIZE + 3) as usize], CellState::Wire);
+        }
+    }
+}
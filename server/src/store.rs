@@ -0,0 +1,386 @@
+//! Keeping a long-lived world in an SQLite database.
+//!
+//! Each chunk is a row keyed by its chunk coordinates, holding the generation it last
+//! changed at, whether it has electrons in it, its share of the world's hash, the corners of
+//! the smallest rectangle holding its cells, and its cells encoded with
+//! `wire_universe::pack::rle_encode`. The generation the whole database was last brought up
+//! to date at is kept alongside. The server reads chunks as it needs them through paging, and
+//! writes changed ones back in batches, so the database can be queried while it runs.
+
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use wire_universe::{
+    pack::{rle_decode, rle_encode},
+    CellState,
+};
+
+use crate::world::paging::{
+    has_electrons, ChunkKey, ChunkStore, ChunkSummary, StoreIndex, StoredChunk, CHUNK_CELLS,
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS chunks (
+        x INTEGER NOT NULL,
+        y INTEGER NOT NULL,
+        generation INTEGER NOT NULL,
+        active INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        hash INTEGER,
+        min_x INTEGER,
+        min_y INTEGER,
+        max_x INTEGER,
+        max_y INTEGER,
+        PRIMARY KEY (x, y)
+    );
+    CREATE INDEX IF NOT EXISTS chunks_by_generation ON chunks (generation);
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+";
+
+/// A chunk's row, without its cells
+#[derive(Copy, Clone, Debug)]
+pub struct ChunkInfo {
+    pub key: ChunkKey,
+    /// the generation the chunk last changed at
+    pub generation: u64,
+    pub active: bool,
+}
+
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating it if needed
+    pub fn open(path: &Path) -> Result<SqliteStore> {
+        let context = || format!("Failed to open database {}", path.display());
+        let conn = Connection::open(path).with_context(context)?;
+        // readers don't block the server's writes, and the other way around
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .with_context(context)?;
+        conn.execute_batch(SCHEMA).with_context(context)?;
+        // databases from before chunks were summarized get the columns, filled in as each
+        // chunk is next written
+        let summarized: bool = conn
+            .query_row(
+                "SELECT count(*) FROM pragma_table_info('chunks') WHERE name = 'hash'",
+                [],
+                |row| row.get(0),
+            )
+            .with_context(context)?;
+        if !summarized {
+            conn.execute_batch(
+                "ALTER TABLE chunks ADD COLUMN hash INTEGER;
+                 ALTER TABLE chunks ADD COLUMN min_x INTEGER;
+                 ALTER TABLE chunks ADD COLUMN min_y INTEGER;
+                 ALTER TABLE chunks ADD COLUMN max_x INTEGER;
+                 ALTER TABLE chunks ADD COLUMN max_y INTEGER;",
+            )
+            .with_context(context)?;
+        }
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// The generation the database was last brought up to date at, if ever
+    pub fn generation(&self) -> Result<Option<u64>> {
+        let conn = self.conn.lock().unwrap();
+        let generation: Option<i64> = conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'generation'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(generation.map(|g| g as u64))
+    }
+
+    /// Chunks written after `generation`, in order of when they were written
+    pub fn chunks_modified_since(&self, generation: u64) -> Result<Vec<ChunkInfo>> {
+        self.query(
+            "SELECT x, y, generation, active FROM chunks WHERE generation > ?1
+             ORDER BY generation, y, x",
+            params![generation as i64],
+        )
+    }
+
+    /// Chunks overlapping the rectangle of chunk coordinates from `from` to `to` inclusive
+    pub fn chunks_in(&self, from: ChunkKey, to: ChunkKey) -> Result<Vec<ChunkInfo>> {
+        self.query(
+            "SELECT x, y, generation, active FROM chunks
+             WHERE x BETWEEN ?1 AND ?3 AND y BETWEEN ?2 AND ?4 ORDER BY y, x",
+            params![from.0, from.1, to.0, to.1],
+        )
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<ChunkInfo>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(sql)?;
+        let rows = statement.query_map(params, |row| {
+            Ok(ChunkInfo {
+                key: (row.get(0)?, row.get(1)?),
+                generation: row.get::<_, i64>(2)? as u64,
+                active: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    // write chunks, and with `commit` the generation the database is up to date at, in one
+    // transaction so a crash can't leave one without the other
+    fn write_chunks(
+        &self,
+        generation: u64,
+        chunks: &[(ChunkKey, Vec<CellState>)],
+        commit: bool,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO chunks
+                 (x, y, generation, active, data, hash, min_x, min_y, max_x, max_y)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?;
+            let mut delete = tx.prepare_cached("DELETE FROM chunks WHERE x = ?1 AND y = ?2")?;
+            for (key, cells) in chunks {
+                match ChunkSummary::of(*key, cells) {
+                    None => {
+                        delete.execute(params![key.0, key.1])?;
+                    }
+                    Some(ChunkSummary {
+                        hash,
+                        bounds: (x0, y0, x1, y1),
+                    }) => {
+                        insert.execute(params![
+                            key.0,
+                            key.1,
+                            generation as i64,
+                            has_electrons(cells),
+                            rle_encode(cells),
+                            hash as i64,
+                            x0,
+                            y0,
+                            x1,
+                            y1
+                        ])?;
+                    }
+                }
+            }
+        }
+        if commit {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('generation', ?1)",
+                params![generation as i64],
+            )?;
+        }
+        tx.commit()
+            .context("Failed to write chunks to the database")
+    }
+}
+
+impl ChunkStore for SqliteStore {
+    fn write(&self, generation: u64, chunks: &[(ChunkKey, Vec<CellState>)]) -> Result<()> {
+        self.write_chunks(generation, chunks, false)
+    }
+
+    fn read(&self, key: ChunkKey) -> Result<Vec<CellState>> {
+        let conn = self.conn.lock().unwrap();
+        let data: Vec<u8> = conn
+            .prepare_cached("SELECT data FROM chunks WHERE x = ?1 AND y = ?2")?
+            .query_row(params![key.0, key.1], |row| row.get(0))
+            .context(format!("Failed to read chunk ({}, {})", key.0, key.1))?;
+        rle_decode(&data, CHUNK_CELLS)
+            .ok_or_else(|| anyhow!("Chunk ({}, {}) is corrupt", key.0, key.1))
+    }
+
+    fn index(&self) -> Result<Option<StoreIndex>> {
+        let Some(generation) = self.generation()? else {
+            return Ok(None);
+        };
+        let conn = self.conn.lock().unwrap();
+        // the cells are only read for chunks written before they were summarized
+        let mut statement = conn.prepare(
+            "SELECT x, y, generation, active, hash, min_x, min_y, max_x, max_y,
+             CASE WHEN hash IS NULL THEN data END FROM chunks",
+        )?;
+        let mut rows = statement.query([])?;
+        let mut chunks = Vec::new();
+        while let Some(row) = rows.next()? {
+            let key = (row.get(0)?, row.get(1)?);
+            let summary = match row.get::<_, Option<i64>>(4)? {
+                Some(hash) => ChunkSummary {
+                    hash: hash as u64,
+                    bounds: (row.get(5)?, row.get(6)?, row.get(7)?, row.get(8)?),
+                },
+                None => {
+                    let data: Vec<u8> = row.get(9)?;
+                    rle_decode(&data, CHUNK_CELLS)
+                        .and_then(|cells| ChunkSummary::of(key, &cells))
+                        .ok_or_else(|| anyhow!("Chunk ({}, {}) is corrupt", key.0, key.1))?
+                }
+            };
+            chunks.push(StoredChunk {
+                key,
+                generation: row.get::<_, i64>(2)? as u64,
+                active: row.get(3)?,
+                summary,
+            });
+        }
+        Ok(Some(StoreIndex { generation, chunks }))
+    }
+
+    fn commit(&self, generation: u64, chunks: &[(ChunkKey, Vec<CellState>)]) -> Result<()> {
+        self.write_chunks(generation, chunks, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use wire_universe::Point;
+
+    use super::*;
+    use crate::{
+        testing::temp_dir,
+        world::{
+            paging::{PagingOptions, CHUNK_SIZE},
+            World,
+        },
+    };
+
+    #[test]
+    fn paging_out_keeps_the_generation() {
+        let dir = temp_dir(&[]);
+        let path = dir.path().join("world.sqlite");
+        let store = Arc::new(SqliteStore::open(&path).unwrap());
+        let mut world = World::new();
+        for x in 0..3 * CHUNK_SIZE {
+            world.set_tile(Point { x, y: 0 }, CellState::Wire).unwrap();
+        }
+        let opts = PagingOptions {
+            budget: 0,
+            cold_after: 1,
+        };
+        world.attach_store(store.clone(), opts).unwrap();
+        for _ in 0..5 {
+            world.step().unwrap();
+        }
+        world
+            .edit_tile(Point { x: 0, y: 1 }, CellState::Wire)
+            .unwrap();
+        for _ in 0..100 {
+            world.step().unwrap();
+        }
+        assert_eq!(world.page_out_cold().unwrap(), 3);
+
+        let changed: Vec<_> = store
+            .chunks_modified_since(0)
+            .unwrap()
+            .into_iter()
+            .map(|info| (info.key, info.generation))
+            .collect();
+        assert_eq!(changed, [((0, 0), 5)]);
+        assert_eq!(store.chunks_in((0, 0), (2, 0)).unwrap().len(), 3);
+
+        // the rows and the generation they're up to date at are written together
+        assert_eq!(store.generation().unwrap(), Some(0));
+        world
+            .edit_tile(Point { x: 1, y: 1 }, CellState::Wire)
+            .unwrap();
+        assert_eq!(world.flush().unwrap(), 1);
+        assert_eq!(store.generation().unwrap(), Some(105));
+        assert_eq!(store.chunks_modified_since(5).unwrap().len(), 1);
+    }
+
+    // a few chunks of wire, one with an electron on it, spread around the origin
+    fn scattered() -> World {
+        let mut world = World::new();
+        for x in -CHUNK_SIZE..2 * CHUNK_SIZE {
+            world.set_tile(Point { x, y: 5 }, CellState::Wire).unwrap();
+            world
+                .set_tile(Point { x: 3, y: x - 40 }, CellState::Wire)
+                .unwrap();
+        }
+        world
+            .set_tile(Point { x: 3, y: -70 }, CellState::Alive)
+            .unwrap();
+        world
+    }
+
+    #[test]
+    fn summaries_outlive_the_server() {
+        let dir = temp_dir(&[]);
+        let path = dir.path().join("world.sqlite");
+        let world = scattered();
+        let opts = PagingOptions {
+            budget: 0,
+            cold_after: 0,
+        };
+        let mut paged = world.clone();
+        paged
+            .attach_store(Arc::new(SqliteStore::open(&path).unwrap()), opts.clone())
+            .unwrap();
+        assert!(paged.page_out_cold().unwrap() > 0);
+        drop(paged);
+
+        let store = Arc::new(SqliteStore::open(&path).unwrap());
+        let read = World::from_store(store, opts).unwrap().unwrap();
+        assert!(read.paged_out() > 0);
+        assert_eq!(read.state_hash(), world.state_hash());
+        assert_eq!(read.bounds(), world.bounds());
+    }
+
+    #[test]
+    fn summarizes_old_databases() {
+        let dir = temp_dir(&[]);
+        let path = dir.path().join("world.sqlite");
+        let world = scattered();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE chunks (
+                x INTEGER NOT NULL,
+                y INTEGER NOT NULL,
+                generation INTEGER NOT NULL,
+                active INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (x, y)
+            );
+            CREATE TABLE meta (key TEXT PRIMARY KEY, value INTEGER NOT NULL);
+            INSERT INTO meta VALUES ('generation', 0);",
+        )
+        .unwrap();
+        let (x0, y0, w, h) = world.bounds().unwrap();
+        for cy in y0.div_euclid(CHUNK_SIZE)..=(y0 + h).div_euclid(CHUNK_SIZE) {
+            for cx in x0.div_euclid(CHUNK_SIZE)..=(x0 + w).div_euclid(CHUNK_SIZE) {
+                let cells = world
+                    .copy_slice(cx * CHUNK_SIZE, cy * CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE)
+                    .concat();
+                if cells.iter().any(|&c| c != CellState::Empty) {
+                    conn.execute(
+                        "INSERT INTO chunks VALUES (?1, ?2, 0, ?3, ?4)",
+                        params![cx, cy, has_electrons(&cells), rle_encode(&cells)],
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        drop(conn);
+
+        let opts = PagingOptions {
+            budget: 0,
+            cold_after: 0,
+        };
+        let store = Arc::new(SqliteStore::open(&path).unwrap());
+        let read = World::from_store(store, opts).unwrap().unwrap();
+        assert_eq!(read.state_hash(), world.state_hash());
+        assert_eq!(read.bounds(), world.bounds());
+    }
+}
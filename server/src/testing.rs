@@ -0,0 +1,29 @@
+//! Helpers shared by the tests.
+
+use tempfile::TempDir;
+use wire_universe::CellState;
+
+use crate::world::World;
+
+/// Every non-empty cell of `world` as `(x, y, cell)`, row by row from the top
+pub fn sorted_cells(world: &World) -> Vec<(i32, i32, CellState)> {
+    let mut cells: Vec<_> = world
+        .cells()
+        .map(|cell| {
+            let (p, c) = cell.unwrap();
+            (p.x, p.y, c)
+        })
+        .collect();
+    cells.sort_by_key(|&(x, y, _)| (y, x));
+    cells
+}
+
+/// A new temporary directory holding `files`, as names and contents, that's deleted when
+/// it's dropped
+pub fn temp_dir(files: &[(&str, &str)]) -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, text) in files {
+        std::fs::write(dir.path().join(name), text).unwrap();
+    }
+    dir
+}
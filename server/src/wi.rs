@@ -0,0 +1,318 @@
+//! Reading the plain text `.wi` format.
+//!
+//! The first line holds the width and height, `<w> <h>`, followed by `h` rows of `w`
+//! characters: `#` for wire, `@` for an electron head, `~` for an electron tail, and a space
+//! or `.` for an empty cell.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use wire_universe::{CellState, Point};
+
+use crate::world::World;
+
+#[derive(Clone, Debug, Default)]
+pub struct WiOptions {
+    /// reject characters that don't stand for a cell, instead of reading them as empty
+    pub strict: bool,
+    /// read missing cells at the end of short rows, and missing rows, as empty
+    pub pad_short_rows: bool,
+}
+
+/// A problem at a position in a `.wi` file; lines and columns count from 1
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+pub struct WiParse {
+    pub world: World,
+    pub warnings: Vec<Diagnostic>,
+}
+
+fn diagnostic(line: usize, column: usize, message: String) -> Diagnostic {
+    Diagnostic {
+        line,
+        column,
+        message,
+    }
+}
+
+// read the next line without its line ending, or `None` at the end of input
+fn next_line<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>, line: usize) -> Result<Option<String>> {
+    buf.clear();
+    if reader.read_until(b'\n', buf)? == 0 {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+    }
+    match std::str::from_utf8(buf) {
+        Ok(s) => Ok(Some(s.to_owned())),
+        Err(e) => {
+            let column = String::from_utf8_lossy(&buf[..e.valid_up_to()])
+                .chars()
+                .count();
+            Err(diagnostic(line, column + 1, "invalid UTF-8".to_owned()).into())
+        }
+    }
+}
+
+fn parse_header(header: &str) -> Result<(usize, usize), Diagnostic> {
+    // whitespace separated fields with the column they start at
+    let mut fields = Vec::new();
+    let mut start = None;
+    for (i, c) in header.chars().chain([' ']).enumerate() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                let field: String = header.chars().skip(s).take(i - s).collect();
+                fields.push((s + 1, field));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    let expected = || "expected the header `<width> <height>'".to_owned();
+    match &fields[..] {
+        [(wc, w), (hc, h)] => {
+            let parse = |column: usize, s: &str| {
+                // every cell and its neighbours have to fit in an i32
+                s.parse::<usize>()
+                    .ok()
+                    .filter(|&n| n <= i32::MAX as usize)
+                    .ok_or_else(|| diagnostic(1, column, format!("`{}' is not a valid size", s)))
+            };
+            Ok((parse(*wc, w)?, parse(*hc, h)?))
+        }
+        [_, _, (column, _), ..] => Err(diagnostic(1, *column, expected())),
+        _ => Err(diagnostic(1, header.chars().count() + 1, expected())),
+    }
+}
+
+/// Read a `.wi` file line by line, so large files never have to fit in memory as text
+pub fn read_wi<R: BufRead>(mut reader: R, opts: &WiOptions) -> Result<WiParse> {
+    let mut buf = Vec::new();
+    let header = next_line(&mut reader, &mut buf, 1)?
+        .ok_or_else(|| diagnostic(1, 1, "empty file, expected a header".to_owned()))?;
+    let (w, h) = parse_header(&header)?;
+
+    let mut world = World::new();
+    let mut warnings = Vec::new();
+    for y in 0..h {
+        let line = y + 2;
+        let Some(row) = next_line(&mut reader, &mut buf, line)? else {
+            let message = format!("expected {} rows, found {}", h, y);
+            if !opts.pad_short_rows {
+                return Err(diagnostic(line, 1, message).into());
+            }
+            warnings.push(diagnostic(line, 1, message + ", reading the rest as empty"));
+            break;
+        };
+        let mut unknown = Vec::new();
+        let mut len = 0;
+        for (x, c) in row.chars().enumerate() {
+            len = x + 1;
+            if x >= w {
+                continue;
+            }
+            let tile = match c {
+                '#' => CellState::Wire,
+                '~' => CellState::Dead,
+                '@' => CellState::Alive,
+                ' ' | '.' => CellState::Empty,
+                _ => {
+                    if opts.strict {
+                        Err(diagnostic(
+                            line,
+                            x + 1,
+                            format!("unknown character {:?}", c),
+                        ))?;
+                    }
+                    unknown.push((x + 1, c));
+                    CellState::Empty
+                }
+            };
+            if tile != CellState::Empty {
+                world.set_tile(
+                    Point {
+                        x: x as i32,
+                        y: y as i32,
+                    },
+                    tile,
+                )?;
+            }
+        }
+        if let Some(&(column, c)) = unknown.first() {
+            let mut message = format!("unknown character {:?} read as empty", c);
+            if unknown.len() > 1 {
+                message += &format!(", as were {} more on this line", unknown.len() - 1);
+            }
+            warnings.push(diagnostic(line, column, message));
+        }
+        if len < w {
+            let message = format!("row is {} cells wide, expected {}", len, w);
+            if !opts.pad_short_rows {
+                return Err(diagnostic(line, len + 1, message).into());
+            }
+            warnings.push(diagnostic(
+                line,
+                len + 1,
+                message + ", reading the rest as empty",
+            ));
+        } else if row.chars().skip(w).any(|c| c != ' ') {
+            warnings.push(diagnostic(
+                line,
+                w + 1,
+                format!("ignoring {} characters past the width", len - w),
+            ));
+        }
+    }
+
+    let mut line = h + 2;
+    while let Some(rest) = next_line(&mut reader, &mut buf, line)? {
+        if !rest.trim().is_empty() {
+            warnings.push(diagnostic(
+                line,
+                1,
+                "ignoring lines past the height".to_owned(),
+            ));
+            break;
+        }
+        line += 1;
+    }
+    Ok(WiParse { world, warnings })
+}
+
+pub fn load_wi(path: &Path, opts: &WiOptions) -> Result<WiParse> {
+    let file = File::open(path).context(format!("Failed to read wi file {}", path.display()))?;
+    read_wi(BufReader::new(file), opts).context(format!("Failed to parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sorted_cells as cells;
+
+    fn parse(text: &str, strict: bool, pad_short_rows: bool) -> Result<WiParse> {
+        let opts = WiOptions {
+            strict,
+            pad_short_rows,
+        };
+        read_wi(text.as_bytes(), &opts)
+    }
+
+    // the diagnostic an error ends in
+    fn error(text: &str, strict: bool, pad_short_rows: bool) -> Diagnostic {
+        let err = parse(text, strict, pad_short_rows).err().unwrap();
+        err.downcast::<Diagnostic>().unwrap()
+    }
+
+    #[test]
+    fn reads_cells() {
+        for text in [
+            "3 2\n#@~\n. #\n",
+            "3 2\r\n#@~\r\n. #",
+            "  3   2 \n#@~\n. #\n\n",
+        ] {
+            let parsed = parse(text, true, false).unwrap();
+            assert!(parsed.warnings.is_empty(), "{:?}", parsed.warnings);
+            assert_eq!(
+                cells(&parsed.world),
+                [
+                    (0, 0, CellState::Wire),
+                    (1, 0, CellState::Alive),
+                    (2, 0, CellState::Dead),
+                    (2, 1, CellState::Wire),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_characters() {
+        let text = "3 1\n#x?\n";
+        let parsed = parse(text, false, false).unwrap();
+        assert_eq!(cells(&parsed.world), [(0, 0, CellState::Wire)]);
+        assert_eq!(
+            parsed.warnings,
+            [diagnostic(
+                2,
+                2,
+                "unknown character 'x' read as empty, as were 1 more on this line".to_owned()
+            )]
+        );
+        assert_eq!(
+            error(text, true, false),
+            diagnostic(2, 2, "unknown character 'x'".to_owned())
+        );
+    }
+
+    #[test]
+    fn short_rows() {
+        let text = "3 3\n#\n###\n";
+        let short = diagnostic(2, 2, "row is 1 cells wide, expected 3".to_owned());
+        assert_eq!(error(text, false, false), short);
+        let missing = diagnostic(4, 1, "expected 3 rows, found 2".to_owned());
+        assert_eq!(error("3 3\n###\n###\n", false, false), missing);
+
+        let parsed = parse(text, true, true).unwrap();
+        assert_eq!(cells(&parsed.world).len(), 4);
+        let lines: Vec<_> = parsed.warnings.iter().map(|d| (d.line, d.column)).collect();
+        assert_eq!(lines, [(2, 2), (4, 1)]);
+    }
+
+    #[test]
+    fn extra_input() {
+        let parsed = parse("2 1\n###\nmore\n", true, false).unwrap();
+        assert_eq!(cells(&parsed.world).len(), 2);
+        let lines: Vec<_> = parsed.warnings.iter().map(|d| (d.line, d.column)).collect();
+        assert_eq!(lines, [(2, 3), (3, 1)]);
+    }
+
+    #[test]
+    fn bad_headers() {
+        for (text, column) in [
+            ("", 1),
+            ("3\n", 2),
+            ("3 2 1\n", 5),
+            ("3 x\n", 3),
+            ("-3 2\n", 1),
+            ("3 99999999999999999999999\n", 3),
+            ("2147483648 1\n", 1),
+        ] {
+            let d = error(text, false, true);
+            assert_eq!((d.line, d.column), (1, column), "{:?}: {}", text, d);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let opts = WiOptions::default();
+        let err = read_wi(&b"2 1\n#\xff\n"[..], &opts).err().unwrap();
+        let d = err.downcast::<Diagnostic>().unwrap();
+        assert_eq!((d.line, d.column), (2, 2));
+    }
+}
@@ -1,8 +1,18 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use wire_universe::{CellState, Point};
 
+use crate::{
+    manifest::load_manifest,
+    wi::{load_wi, WiOptions},
+};
+
+pub mod paging;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
 enum CellStateInternal {
@@ -29,71 +39,75 @@ fn cell_state_admit(c: CellState) -> Option<CellStateInternal> {
     }
 }
 
-#[derive(Clone, Debug)]
+/// Whether a `w` by `h` region at (`x`, `y`) can hold cells. `set_tile` works on the
+/// neighbours of a cell too, so they have to be in range as well.
+pub fn region_fits(x: i32, y: i32, w: i32, h: i32) -> bool {
+    w > 0
+        && h > 0
+        && x > i32::MIN
+        && y > i32::MIN
+        && x.checked_add(w).is_some()
+        && y.checked_add(h).is_some()
+}
+
+// a cell's share of `World::state_hash`. The shares are summed, so the hash doesn't depend on
+// the order cells are visited in and a paged out chunk's share can be kept without its cells.
+pub(crate) fn cell_hash(p: Point, c: CellState) -> u64 {
+    // the finalizer of splitmix64
+    fn mix(mut z: u64) -> u64 {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    mix(mix((p.x as u32 as u64) << 32 | p.y as u32 as u64) ^ c.to_bits() as u64)
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct World {
     pts: HashMap<Point, usize>,
     pts_r: HashMap<usize, Point>,
     sts: Vec<CellStateInternal>,
     nbors: Vec<Vec<usize>>,
+    generation: u64,
+    paging: Option<paging::Paging>,
+    // chunks edited since `clear_edited` was last called
+    edited: HashSet<paging::ChunkKey>,
 }
 
 impl World {
     pub fn new() -> World {
-        World {
-            pts: HashMap::new(),
-            pts_r: HashMap::new(),
-            sts: Vec::new(),
-            nbors: Vec::new(),
-        }
+        World::default()
     }
 
     pub fn from_wi(path: &Path) -> Result<World> {
-        let data =
-            std::fs::read(path).context(format!("Failed to read wi file {}", path.display()))?;
-        let string = std::str::from_utf8(&data).context(format!(
-            "File {} contained non utf-8 characters",
-            path.display()
-        ))?;
-        let lines: Vec<_> = string.lines().collect();
-        let result = (|| -> Result<World> {
-            let (bounds, data) = lines
-                .split_first()
-                .ok_or_else(|| anyhow!("Too few lines"))?;
-            let (w, h) = match bounds.split_ascii_whitespace().collect::<Vec<_>>() {
-                v if v.len() == 2 => (v[0].parse::<usize>()?, v[1].parse::<usize>()?),
-                _ => Err(anyhow!("First line format not formatted '<w> <h>'"))?,
-            };
-            let mut world = World::new();
-            for y in 0..h {
-                for x in 0..w {
-                    let val = data
-                        .get(y)
-                        .and_then(|row| row.as_bytes().get(x))
-                        .ok_or_else(|| anyhow!("Dimensions incorrect"))?;
-                    let tile = match val {
-                        b'#' => Some(CellState::Wire),
-                        b'~' => Some(CellState::Dead),
-                        b'@' => Some(CellState::Alive),
-                        _ => None,
-                    };
-                    if let Some(tile) = tile {
-                        world.set_tile(
-                            Point {
-                                x: x as i32,
-                                y: y as i32,
-                            },
-                            tile,
-                        );
-                    }
-                }
-            }
-            Ok(world)
-        })();
-        let world = result.context(format!("Failed to parse {}", path.display()))?;
-        Ok(world)
+        Ok(load_wi(path, &WiOptions::default())?.world)
+    }
+
+    /// Load a `.wi` file, `.wus` snapshot or `.toml` manifest, going by the extension
+    pub fn load(path: &Path) -> Result<World> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("wi") => World::from_wi(path),
+            Some("wus") => World::load_snapshot(path),
+            Some("toml") => load_manifest(path),
+            _ => Err(anyhow!(
+                "Unknown world format for {}, expected .wi, .wus or .toml",
+                path.display()
+            )),
+        }
+    }
+
+    /// Set a tile, first reading its chunk back in if it's paged out, which can fail
+    pub fn set_tile(&mut self, pos: Point, s: CellState) -> Result<()> {
+        if self.is_paged_out(pos) {
+            self.page_in(paging::chunk_key(pos))?;
+        }
+        self.put_tile(pos, s);
+        Ok(())
     }
 
-    pub fn set_tile(&mut self, pos: Point, s: CellState) {
+    // set a tile in memory, whether or not its chunk is paged out
+    fn put_tile(&mut self, pos: Point, s: CellState) {
+        self.mark_dirty(pos);
         // TODO needs testing
         match cell_state_admit(s) {
             Some(s) => {
@@ -179,7 +193,65 @@ impl World {
         };
     }
 
-    pub fn step(&mut self) {
+    /// Set a tile on someone's behalf, noting its chunk as edited
+    pub fn edit_tile(&mut self, pos: Point, s: CellState) -> Result<()> {
+        self.set_tile(pos, s)?;
+        let key = paging::chunk_key(pos);
+        self.edited.insert(key);
+        if let Some(paging) = &mut self.paging {
+            paging.modified(key, self.generation);
+        }
+        Ok(())
+    }
+
+    /// The number of times the world has been stepped
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The number of cells in memory, which stepping takes time in proportion to
+    pub fn resident(&self) -> usize {
+        self.sts.len()
+    }
+
+    pub(crate) fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Whether a chunk has been edited with `edit_tile` since `clear_edited` was last called,
+    /// as opposed to only changing by stepping
+    pub fn was_edited(&self, key: paging::ChunkKey) -> bool {
+        self.edited.contains(&key)
+    }
+
+    pub fn clear_edited(&mut self) {
+        self.edited.clear();
+    }
+
+    /// Every non-empty cell, in no particular order, including any paged out. Reading a
+    /// paged out chunk can fail, which ends up as an error in place of its cells.
+    pub fn cells(&self) -> impl Iterator<Item = Result<(Point, CellState)>> + '_ {
+        self.pts_r
+            .iter()
+            .map(|(&i, &p)| Ok((p, cell_state_expel(Some(self.sts[i])))))
+            .chain(self.paged_cells())
+    }
+
+    /// A hash of every cell, the same for equal worlds regardless of how they were built or
+    /// which of their chunks are paged out
+    pub fn state_hash(&self) -> u64 {
+        self.pts_r
+            .iter()
+            .map(|(&i, &p)| cell_hash(p, cell_state_expel(Some(self.sts[i]))))
+            .chain(self.paged_summaries().map(|summary| summary.hash))
+            .fold(0, u64::wrapping_add)
+    }
+
+    /// Step the world a generation, first reading back any paged out chunk an electron is
+    /// about to reach. Fails without stepping if one of them can't be read.
+    pub fn step(&mut self) -> Result<()> {
+        self.page_in_frontier()?;
+        self.generation += 1;
         let mut adj = vec![0; self.sts.len()];
         for (i, st) in self.sts.iter().enumerate() {
             if *st == CellStateInternal::Alive {
@@ -189,7 +261,7 @@ impl World {
             }
         }
         for (i, &n) in adj.iter().enumerate() {
-            self.sts[i] = match self.sts[i] {
+            let st = match self.sts[i] {
                 CellStateInternal::Alive => CellStateInternal::Dead,
                 CellStateInternal::Dead => CellStateInternal::Wire,
                 CellStateInternal::Wire => {
@@ -199,20 +271,32 @@ impl World {
                         CellStateInternal::Wire
                     }
                 }
+            };
+            if st != self.sts[i] {
+                self.sts[i] = st;
+                if let Some(paging) = &mut self.paging {
+                    paging.modified(paging::chunk_key(self.pts_r[&i]), self.generation);
+                }
             }
         }
+        Ok(())
     }
 
+    /// The cells of a rectangle row by row, with any past the edge of the coordinate range
+    /// read as empty
     pub fn copy_slice(&self, x: i32, y: i32, w: i32, h: i32) -> Vec<Vec<CellState>> {
         let mut ret = Vec::new();
-        for j in y..(y + h) {
+        for dy in 0..h {
             let mut row = Vec::new();
-            for i in x..(x + w) {
-                row.push(self.get_tile_out(Point { x: i, y: j }));
+            for dx in 0..w {
+                row.push(match (x.checked_add(dx), y.checked_add(dy)) {
+                    (Some(x), Some(y)) => self.get_tile_out(Point { x, y }),
+                    _ => CellState::Empty,
+                });
             }
             ret.push(row);
         }
-        return ret;
+        ret
     }
 
     fn get_tile(&self, p: Point) -> Option<CellStateInternal> {
@@ -220,10 +304,14 @@ impl World {
     }
 
     pub fn get_tile_out(&self, p: Point) -> CellState {
+        if self.is_paged_out(p) {
+            return self.read_paged(p);
+        }
         cell_state_expel(self.get_tile(p))
     }
 
-    // returns the perimeter in the method expected by `PartialRefresh'
+    // returns the perimeter in the order expected by `ChunkRing`, counter-clockwise from the
+    // top left
     pub fn copy_perimeter(&self, x: i32, y: i32, w: i32, h: i32) -> Vec<CellState> {
         let mut p = vec![];
         for dy in 0..h {
@@ -235,24 +323,36 @@ impl World {
                 y: y + h - 1,
             }));
         }
-        for dy in (1..h).rev() {
+        for dy in (1..h - 1).rev() {
             p.push(self.get_tile_out(Point {
                 x: x + w - 1,
                 y: y + dy,
             }));
         }
-        for dx in (1..w - 1).rev() {
+        for dx in (1..w).rev() {
             p.push(self.get_tile_out(Point { x: x + dx, y }));
         }
-        return p;
+        p
+    }
+
+    /// The smallest rectangle containing every non-empty cell, as `(x, y, w, h)`
+    pub fn bounds(&self) -> Option<(i32, i32, i32, i32)> {
+        self.pts_r
+            .values()
+            .map(|p| (p.x, p.y, p.x, p.y))
+            .chain(self.paged_summaries().map(|summary| summary.bounds))
+            .reduce(|(x0, y0, x1, y1), (a0, b0, a1, b1)| {
+                (x0.min(a0), y0.min(b0), x1.max(a1), y1.max(b1))
+            })
+            .map(|(x0, y0, x1, y1)| (x0, y0, x1 - x0 + 1, y1 - y0 + 1))
     }
 }
 
 pub fn sample_world() -> World {
     let mut world = World::new();
-    world.set_tile(Point { x: 1, y: 0 }, CellState::Alive);
-    world.set_tile(Point { x: 0, y: 1 }, CellState::Dead);
-    world.set_tile(Point { x: 1, y: 2 }, CellState::Wire);
-    world.set_tile(Point { x: 2, y: 1 }, CellState::Wire);
-    return world;
+    world.put_tile(Point { x: 1, y: 0 }, CellState::Alive);
+    world.put_tile(Point { x: 0, y: 1 }, CellState::Dead);
+    world.put_tile(Point { x: 1, y: 2 }, CellState::Wire);
+    world.put_tile(Point { x: 2, y: 1 }, CellState::Wire);
+    world
 }
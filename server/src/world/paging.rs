@@ -0,0 +1,656 @@
+//! Keeping the quiet parts of a large world on disk.
+//!
+//! The world is split into `CHUNK_SIZE` square chunks. Once more cells are in memory than the
+//! budget allows, chunks holding nothing but wire, with no electrons in or next to them, are
+//! written to a `ChunkStore` and dropped from the world. Such a chunk can't change until an
+//! electron reaches its edge, so before each step any stored chunk next to an electron is read
+//! back in, and stepping gives the same result as if it had never left. Edits to stored cells
+//! read the chunk back first, and fail if it can't be read. Each stored chunk's share of the
+//! world's hash and bounds is kept in memory, so those never go to the store.
+//!
+//! Reads of stored cells go through to the store. `World::cells` gives an error for a chunk
+//! that can't be read, since whatever is being written from it would be missing the chunk,
+//! while cells read to be shown to someone are shown as empty.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use wire_universe::{
+    pack::{rle_decode, rle_encode},
+    CellState, Point,
+};
+
+use super::{cell_hash, CellStateInternal, World};
+
+pub use wire_universe::proto::CHUNK_SIZE;
+pub const CHUNK_CELLS: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+/// Chunk coordinates, in units of `CHUNK_SIZE` cells
+pub type ChunkKey = (i32, i32);
+
+pub fn chunk_key(p: Point) -> ChunkKey {
+    (p.x.div_euclid(CHUNK_SIZE), p.y.div_euclid(CHUNK_SIZE))
+}
+
+/// The index of `p` within its chunk's cells, row by row
+pub fn chunk_index(p: Point) -> usize {
+    (p.y.rem_euclid(CHUNK_SIZE) * CHUNK_SIZE + p.x.rem_euclid(CHUNK_SIZE)) as usize
+}
+
+/// The top left cell of a chunk
+pub fn chunk_origin(key: ChunkKey) -> Point {
+    Point {
+        x: key.0 * CHUNK_SIZE,
+        y: key.1 * CHUNK_SIZE,
+    }
+}
+
+/// Somewhere to keep chunks that have been paged out, as `CHUNK_CELLS` cells row by row
+pub trait ChunkStore: Debug + Send + Sync {
+    /// Store chunks as they are at `generation`, all at once if the store can. An empty chunk
+    /// may be removed.
+    fn write(&self, generation: u64, chunks: &[(ChunkKey, Vec<CellState>)]) -> Result<()>;
+    fn read(&self, key: ChunkKey) -> Result<Vec<CellState>>;
+
+    /// What an earlier run left in the store, for stores that outlive the server
+    fn index(&self) -> Result<Option<StoreIndex>> {
+        Ok(None)
+    }
+
+    /// Store chunks as `write` does, and note that the store now holds the whole world as
+    /// of `generation`, all at once for stores that outlive the server
+    fn commit(&self, generation: u64, chunks: &[(ChunkKey, Vec<CellState>)]) -> Result<()> {
+        self.write(generation, chunks)
+    }
+}
+
+/// The chunks in a store, and the generation it was last committed at
+#[derive(Clone, Debug)]
+pub struct StoreIndex {
+    pub generation: u64,
+    pub chunks: Vec<StoredChunk>,
+}
+
+/// A chunk in a store, without its cells
+#[derive(Copy, Clone, Debug)]
+pub struct StoredChunk {
+    pub key: ChunkKey,
+    /// the generation the chunk was last written at
+    pub generation: u64,
+    /// whether it has electrons in it
+    pub active: bool,
+    pub summary: ChunkSummary,
+}
+
+/// What the rest of the world needs to know about a paged out chunk, so the chunk needn't be
+/// read back for it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkSummary {
+    /// the chunk's share of `World::state_hash`
+    pub hash: u64,
+    /// the smallest rectangle holding its cells, as the corners `(x0, y0, x1, y1)` inclusive
+    pub bounds: (i32, i32, i32, i32),
+}
+
+impl ChunkSummary {
+    /// Summarize the cells of the chunk `key`, if any aren't empty
+    pub fn of(key: ChunkKey, cells: &[CellState]) -> Option<ChunkSummary> {
+        let origin = chunk_origin(key);
+        let mut summary: Option<ChunkSummary> = None;
+        for (i, &cell) in cells.iter().enumerate() {
+            if cell == CellState::Empty {
+                continue;
+            }
+            let p = Point {
+                x: origin.x + i as i32 % CHUNK_SIZE,
+                y: origin.y + i as i32 / CHUNK_SIZE,
+            };
+            let hash = cell_hash(p, cell);
+            summary = Some(match summary {
+                None => ChunkSummary {
+                    hash,
+                    bounds: (p.x, p.y, p.x, p.y),
+                },
+                Some(ChunkSummary {
+                    hash: h,
+                    bounds: (x0, y0, x1, y1),
+                }) => ChunkSummary {
+                    hash: h.wrapping_add(hash),
+                    bounds: (x0.min(p.x), y0.min(p.y), x1.max(p.x), y1.max(p.y)),
+                },
+            });
+        }
+        summary
+    }
+}
+
+/// Whether any cell is an electron head or tail
+pub fn has_electrons(cells: &[CellState]) -> bool {
+    cells
+        .iter()
+        .any(|&c| c == CellState::Alive || c == CellState::Dead)
+}
+
+/// A directory with a file per chunk
+#[derive(Debug)]
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    /// Use `dir` for chunks, creating it if needed. Chunks left over from an earlier run are
+    /// deleted, since the world they belonged to is gone.
+    pub fn open(dir: &Path) -> Result<DirStore> {
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+        for entry in fs::read_dir(dir).context(format!("Failed to list {}", dir.display()))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "chunk") {
+                fs::remove_file(&path).context(format!("Failed to remove {}", path.display()))?;
+            }
+        }
+        Ok(DirStore {
+            dir: dir.to_owned(),
+        })
+    }
+
+    fn path(&self, key: ChunkKey) -> PathBuf {
+        self.dir.join(format!("{}_{}.chunk", key.0, key.1))
+    }
+}
+
+impl ChunkStore for DirStore {
+    fn write(&self, _generation: u64, chunks: &[(ChunkKey, Vec<CellState>)]) -> Result<()> {
+        for (key, cells) in chunks {
+            let path = self.path(*key);
+            fs::write(&path, rle_encode(cells))
+                .context(format!("Failed to write chunk {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, key: ChunkKey) -> Result<Vec<CellState>> {
+        let path = self.path(key);
+        let data = fs::read(&path).context(format!("Failed to read chunk {}", path.display()))?;
+        rle_decode(&data, CHUNK_CELLS)
+            .with_context(|| format!("Chunk {} is corrupt", path.display()))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PagingOptions {
+    /// roughly how many cells to keep in memory
+    pub budget: usize,
+    /// how many generations a chunk stays in memory after it was last looked at or active
+    pub cold_after: u64,
+}
+
+// stored chunks most recently read, shared between clones of the world
+const CACHE_CHUNKS: usize = 256;
+
+#[derive(Clone, Debug)]
+pub(super) struct Paging {
+    store: Arc<dyn ChunkStore>,
+    opts: PagingOptions,
+    evicted: HashMap<ChunkKey, ChunkSummary>,
+    // chunks edited since the last flush, and those with electrons at the last flush
+    dirty: HashSet<ChunkKey>,
+    last_busy: HashSet<ChunkKey>,
+    // the generation each chunk was last viewed or near an electron
+    touched: HashMap<ChunkKey, u64>,
+    // the generation each chunk last changed at, for those changed since `since`, so a chunk
+    // paged out long after it went quiet isn't stored as new
+    modified: HashMap<ChunkKey, u64>,
+    since: u64,
+    cache: Arc<Mutex<HashMap<ChunkKey, Arc<Vec<CellState>>>>>,
+}
+
+impl Paging {
+    fn read(&self, key: ChunkKey) -> Result<Arc<Vec<CellState>>> {
+        if let Some(cells) = self.cache.lock().unwrap().get(&key) {
+            return Ok(cells.clone());
+        }
+        let cells = Arc::new(self.store.read(key)?);
+        self.remember(key, cells.clone());
+        Ok(cells)
+    }
+
+    pub(super) fn modified(&mut self, key: ChunkKey, generation: u64) {
+        self.modified.insert(key, generation);
+    }
+
+    fn remember(&self, key: ChunkKey, cells: Arc<Vec<CellState>>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_CHUNKS {
+            cache.clear();
+        }
+        cache.insert(key, cells);
+    }
+}
+
+impl World {
+    /// Start paging chunks out to `store` once the world grows past the budget
+    pub fn enable_paging(&mut self, store: Arc<dyn ChunkStore>, opts: PagingOptions) {
+        self.paging = Some(Paging {
+            store,
+            opts,
+            evicted: HashMap::new(),
+            dirty: HashSet::new(),
+            last_busy: HashSet::new(),
+            touched: HashMap::new(),
+            modified: HashMap::new(),
+            since: self.generation,
+            cache: Default::default(),
+        });
+    }
+
+    /// Load the world an earlier run left in `store`, if any. Only chunks with electrons are
+    /// read straight away, the rest as they're needed.
+    pub fn from_store(store: Arc<dyn ChunkStore>, opts: PagingOptions) -> Result<Option<World>> {
+        let Some(index) = store.index()? else {
+            return Ok(None);
+        };
+        let mut world = World::new();
+        world.set_generation(index.generation);
+        world.enable_paging(store, opts);
+        let paging = world.paging.as_mut().unwrap();
+        paging
+            .evicted
+            .extend(index.chunks.iter().map(|chunk| (chunk.key, chunk.summary)));
+        for chunk in &index.chunks {
+            if chunk.active {
+                world.page_in(chunk.key)?;
+            }
+        }
+        let paging = world.paging.as_mut().unwrap();
+        paging.dirty.clear();
+        paging.modified = index
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.key, chunk.generation))
+            .collect();
+        paging.last_busy = index
+            .chunks
+            .into_iter()
+            .filter(|chunk| chunk.active)
+            .map(|chunk| chunk.key)
+            .collect();
+        Ok(Some(world))
+    }
+
+    /// Write the whole world to `store` and keep it there from now on, paging out to it too
+    pub fn attach_store(&mut self, store: Arc<dyn ChunkStore>, opts: PagingOptions) -> Result<()> {
+        let mut keys: Vec<_> = self.pts.keys().map(|&p| chunk_key(p)).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let chunks: Vec<_> = keys
+            .into_iter()
+            .map(|key| (key, self.resident_chunk(key).0))
+            .collect();
+        store.commit(self.generation, &chunks)?;
+        self.enable_paging(store, opts);
+        let paging = self.paging.as_mut().unwrap();
+        paging.last_busy = chunks
+            .iter()
+            .filter(|(_, cells)| has_electrons(cells))
+            .map(|&(key, _)| key)
+            .collect();
+        Ok(())
+    }
+
+    /// Write every chunk that may have changed since the last flush to the store in one batch,
+    /// and commit it. Returns how many chunks were written.
+    pub fn flush(&mut self) -> Result<usize> {
+        let Some(paging) = &self.paging else {
+            return Ok(0);
+        };
+        let mut busy = HashSet::new();
+        for (i, &st) in self.sts.iter().enumerate() {
+            if st != CellStateInternal::Wire {
+                busy.insert(chunk_key(self.pts_r[&i]));
+            }
+        }
+        // a chunk with no electrons now or last time, and no edits, is just as it was
+        let mut keys: Vec<_> = busy
+            .iter()
+            .chain(&paging.last_busy)
+            .chain(&paging.dirty)
+            .filter(|key| !paging.evicted.contains_key(key))
+            .copied()
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        let chunks: Vec<_> = keys
+            .into_iter()
+            .map(|key| (key, self.resident_chunk(key).0))
+            .collect();
+        paging.store.commit(self.generation, &chunks)?;
+        let paging = self.paging.as_mut().unwrap();
+        paging.dirty.clear();
+        paging.last_busy = busy;
+        Ok(chunks.len())
+    }
+
+    pub(super) fn mark_dirty(&mut self, p: Point) {
+        if let Some(paging) = &mut self.paging {
+            paging.dirty.insert(chunk_key(p));
+        }
+    }
+
+    /// Bring every paged out chunk back into memory and stop paging, so the world no longer
+    /// depends on its store
+    pub fn detach(&mut self) -> Result<()> {
+        let Some(paging) = &self.paging else {
+            return Ok(());
+        };
+        for key in paging.evicted.keys().copied().collect::<Vec<_>>() {
+            self.page_in(key)?;
+        }
+        self.paging = None;
+        Ok(())
+    }
+
+    /// How many chunks are currently paged out
+    pub fn paged_out(&self) -> usize {
+        self.paging.as_ref().map_or(0, |p| p.evicted.len())
+    }
+
+    pub(super) fn is_paged_out(&self, p: Point) -> bool {
+        self.paging
+            .as_ref()
+            .is_some_and(|paging| paging.evicted.contains_key(&chunk_key(p)))
+    }
+
+    // the summary of every paged out chunk
+    pub(super) fn paged_summaries(&self) -> impl Iterator<Item = &ChunkSummary> + '_ {
+        self.paging
+            .iter()
+            .flat_map(|paging| paging.evicted.values())
+    }
+
+    // the cell at `p` in a paged out chunk to be shown, or empty if the chunk can't be read
+    pub(super) fn read_paged(&self, p: Point) -> CellState {
+        let paging = self.paging.as_ref().unwrap();
+        match paging.read(chunk_key(p)) {
+            Ok(cells) => cells[chunk_index(p)],
+            Err(e) => {
+                log::error!("Reading a paged out chunk as empty: {:#}", e);
+                CellState::Empty
+            }
+        }
+    }
+
+    // the non-empty cells of every paged out chunk, read one chunk at a time, with an error
+    // in place of the cells of any chunk that can't be read
+    pub(super) fn paged_cells(&self) -> impl Iterator<Item = Result<(Point, CellState)>> + '_ {
+        let paging = self.paging.as_ref();
+        paging
+            .into_iter()
+            .flat_map(|paging| paging.evicted.keys())
+            .flat_map(move |&key| {
+                let (cells, error) = match paging.unwrap().read(key) {
+                    Ok(cells) => (Some(cells), None),
+                    Err(e) => (None, Some(Err(e))),
+                };
+                let origin = chunk_origin(key);
+                let cells = cells.into_iter().flat_map(move |cells| {
+                    (0..CHUNK_CELLS).filter_map(move |i| {
+                        let p = Point {
+                            x: origin.x + i as i32 % CHUNK_SIZE,
+                            y: origin.y + i as i32 / CHUNK_SIZE,
+                        };
+                        Some((p, cells[i])).filter(|&(_, c)| c != CellState::Empty)
+                    })
+                });
+                error.into_iter().chain(cells.map(Ok))
+            })
+    }
+
+    /// Bring every chunk overlapping the rectangle back into memory and keep it there for a
+    /// while, since someone is looking at it
+    pub fn touch(&mut self, x: i32, y: i32, w: i32, h: i32) -> Result<()> {
+        if self.paging.is_none() || w <= 0 || h <= 0 {
+            return Ok(());
+        }
+        let (cx0, cy0) = chunk_key(Point { x, y });
+        let (cx1, cy1) = chunk_key(Point {
+            x: x.saturating_add(w - 1),
+            y: y.saturating_add(h - 1),
+        });
+        for cy in cy0..=cy1 {
+            for cx in cx0..=cx1 {
+                self.page_in((cx, cy))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep chunks in memory for a while longer, bringing back any that were paged out
+    pub fn touch_chunks(&mut self, keys: impl IntoIterator<Item = ChunkKey>) -> Result<()> {
+        if self.paging.is_none() {
+            return Ok(());
+        }
+        for key in keys {
+            self.page_in(key)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn page_in(&mut self, key: ChunkKey) -> Result<()> {
+        let generation = self.generation;
+        let Some(paging) = &mut self.paging else {
+            return Ok(());
+        };
+        paging.touched.insert(key, generation);
+        if !paging.evicted.contains_key(&key) {
+            return Ok(());
+        }
+        let cells = paging
+            .read(key)
+            .context(format!("Failed to page in chunk ({}, {})", key.0, key.1))?;
+        paging.evicted.remove(&key);
+        let origin = chunk_origin(key);
+        for (i, &cell) in cells.iter().enumerate() {
+            if cell != CellState::Empty {
+                let i = i as i32;
+                self.put_tile(
+                    Point {
+                        x: origin.x + i % CHUNK_SIZE,
+                        y: origin.y + i / CHUNK_SIZE,
+                    },
+                    cell,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // bring back every paged out chunk next to an electron, ready for the next step
+    pub(super) fn page_in_frontier(&mut self) -> Result<()> {
+        let Some(paging) = &self.paging else {
+            return Ok(());
+        };
+        if paging.evicted.is_empty() {
+            return Ok(());
+        }
+        let mut active = HashSet::new();
+        for (i, &st) in self.sts.iter().enumerate() {
+            if st == CellStateInternal::Alive {
+                active.insert(chunk_key(self.pts_r[&i]));
+            }
+        }
+        let mut wanted = Vec::new();
+        for (cx, cy) in active {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let key = (cx + dx, cy + dy);
+                    if paging.evicted.contains_key(&key) {
+                        wanted.push(key);
+                    }
+                }
+            }
+        }
+        for key in wanted {
+            self.page_in(key)?;
+        }
+        Ok(())
+    }
+
+    /// Page out cold chunks until the world is back under its memory budget, or nothing else
+    /// can go. Returns how many chunks were paged out.
+    pub fn page_out_cold(&mut self) -> Result<usize> {
+        let generation = self.generation;
+        let Some(paging) = &self.paging else {
+            return Ok(0);
+        };
+        if self.sts.len() <= paging.opts.budget {
+            return Ok(0);
+        }
+
+        // cells in each chunk, and the chunks with electrons in them
+        let mut counts: HashMap<ChunkKey, usize> = HashMap::new();
+        let mut busy = HashSet::new();
+        for (&i, &p) in &self.pts_r {
+            let key = chunk_key(p);
+            *counts.entry(key).or_default() += 1;
+            if self.sts[i] != CellStateInternal::Wire {
+                busy.insert(key);
+            }
+        }
+        let near_busy = |(cx, cy): ChunkKey| {
+            (-1..=1).any(|dy| (-1..=1).any(|dx| busy.contains(&(cx + dx, cy + dy))))
+        };
+        let mut candidates: Vec<(u64, ChunkKey)> = counts
+            .keys()
+            .map(|&key| (paging.touched.get(&key).copied().unwrap_or(0), key))
+            .filter(|&(touched, key)| {
+                generation.saturating_sub(touched) >= paging.opts.cold_after && !near_busy(key)
+            })
+            .collect();
+        candidates.sort_unstable();
+
+        let mut count = 0;
+        for (_, key) in candidates {
+            if self.sts.len() <= self.paging.as_ref().unwrap().opts.budget {
+                break;
+            }
+            self.page_out(key)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // the cells of a chunk in memory, and where the non-empty ones are
+    fn resident_chunk(&self, key: ChunkKey) -> (Vec<CellState>, Vec<Point>) {
+        let origin = chunk_origin(key);
+        let mut cells = vec![CellState::Empty; CHUNK_CELLS];
+        let mut pts = Vec::new();
+        for (i, cell) in cells.iter_mut().enumerate() {
+            let p = Point {
+                x: origin.x + i as i32 % CHUNK_SIZE,
+                y: origin.y + i as i32 / CHUNK_SIZE,
+            };
+            if let Some(&n) = self.pts.get(&p) {
+                *cell = super::cell_state_expel(Some(self.sts[n]));
+                pts.push(p);
+            }
+        }
+        (cells, pts)
+    }
+
+    fn page_out(&mut self, key: ChunkKey) -> Result<()> {
+        let (cells, pts) = self.resident_chunk(key);
+        let Some(summary) = ChunkSummary::of(key, &cells) else {
+            return Ok(());
+        };
+        let paging = self.paging.as_mut().unwrap();
+        let modified = paging.modified.get(&key).copied().unwrap_or(paging.since);
+        paging
+            .store
+            .write(modified, &[(key, cells.clone())])
+            .context(format!("Failed to page out chunk ({}, {})", key.0, key.1))?;
+        paging.remember(key, Arc::new(cells));
+        paging.touched.remove(&key);
+        paging.dirty.remove(&key);
+        for p in pts {
+            self.put_tile(p, CellState::Empty);
+        }
+        self.paging.as_mut().unwrap().evicted.insert(key, summary);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::testing::temp_dir;
+
+    // a world with a line of wire across `chunks` chunks, paging to a fresh directory
+    fn wired(chunks: i32) -> (World, TempDir) {
+        let dir = temp_dir(&[]);
+        let mut world = World::new();
+        for x in 0..chunks * CHUNK_SIZE {
+            world.set_tile(Point { x, y: 0 }, CellState::Wire).unwrap();
+        }
+        let opts = PagingOptions {
+            budget: 0,
+            cold_after: 1,
+        };
+        world.enable_paging(Arc::new(DirStore::open(dir.path()).unwrap()), opts);
+        (world, dir)
+    }
+
+    #[test]
+    fn watched_chunks_stay() {
+        let (mut world, _dir) = wired(4);
+        for generation in 1..10 {
+            world.set_generation(generation);
+            world.touch_chunks([(1, 0)]).unwrap();
+        }
+        assert_eq!(world.page_out_cold().unwrap(), 3);
+        let paging = world.paging.as_ref().unwrap();
+        assert!(!paging.evicted.contains_key(&(1, 0)));
+        assert_eq!(world.cells().count(), 4 * CHUNK_SIZE as usize);
+    }
+
+    #[test]
+    fn lost_chunks() {
+        let (mut world, dir) = wired(2);
+        let (hash, bounds) = (world.state_hash(), world.bounds());
+        world.set_generation(10);
+        assert_eq!(world.page_out_cold().unwrap(), 2);
+        std::fs::remove_file(dir.path().join("0_0.chunk")).unwrap();
+        world.paging.as_ref().unwrap().cache.lock().unwrap().clear();
+
+        // shown as empty, but the hash and bounds don't need the chunk
+        assert_eq!(world.get_tile_out(Point { x: 0, y: 0 }), CellState::Empty);
+        assert_eq!(
+            world.get_tile_out(Point {
+                x: CHUNK_SIZE,
+                y: 0
+            }),
+            CellState::Wire
+        );
+        assert_eq!((world.state_hash(), world.bounds()), (hash, bounds));
+        let cells: Vec<_> = world.cells().collect();
+        assert_eq!(cells.len(), CHUNK_SIZE as usize + 1);
+        assert_eq!(cells.iter().filter(|cell| cell.is_err()).count(), 1);
+
+        // anything that would change the chunk fails, leaving the world as it was
+        assert!(world
+            .set_tile(Point { x: 1, y: 0 }, CellState::Alive)
+            .is_err());
+        world
+            .set_tile(Point { x: 0, y: -1 }, CellState::Alive)
+            .unwrap();
+        assert!(world.step().is_err());
+        assert_eq!(world.generation(), 10);
+    }
+}
@@ -0,0 +1,324 @@
+//! The set of worlds one server hosts.
+//!
+//! Worlds come from the configuration, which can't be changed while running, or are created
+//! while running. With a data directory, each created world keeps its settings, autosaves
+//! and journal in a subdirectory named after it, and is started again along with the server:
+//!
+//! ```text
+//! <data dir>/<name>/world.toml   settings, as in a `[worlds.<name>]` table of the config
+//! <data dir>/<name>/seed.wus     the world it started as, if copied from another
+//! <data dir>/<name>/saves/       autosaves
+//! <data dir>/<name>/journal
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+use crate::{
+    config::{AutosaveConfig, WorldConfig},
+    host::{WorldHandle, WorldOptions},
+    world::World,
+};
+
+/// The name of the world served at `/ws`
+pub const DEFAULT_WORLD: &str = "default";
+
+const SETTINGS: &str = "world.toml";
+const SEED: &str = "seed.wus";
+
+pub struct Worlds {
+    worlds: RwLock<BTreeMap<String, Arc<Hosted>>>,
+    data_dir: Option<PathBuf>,
+    // no more can be created once this many are hosted
+    max_worlds: usize,
+    // held while creating or deleting, so two requests can't race over a name
+    changes: tokio::sync::Mutex<()>,
+}
+
+pub struct Hosted {
+    pub handle: WorldHandle,
+    /// created while running, as opposed to configured
+    pub created: bool,
+}
+
+/// Why `Worlds::create` turned a world down, as opposed to failing to create it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refused {
+    Taken(String),
+    /// as many worlds as allowed are already hosted
+    Full(usize),
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refused::Taken(name) => write!(f, "There is already a world called `{}'", name),
+            Refused::Full(max) => write!(f, "There are already {} worlds, the most allowed", max),
+        }
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Names are used in paths and URLs, so only letters, digits, `-` and `_` are allowed
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 64 {
+        bail!("World names must be 1 to 64 characters long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("World names may only contain letters, digits, `-' and `_'");
+    }
+    Ok(())
+}
+
+impl Worlds {
+    /// Start the configured worlds, then any created in an earlier run
+    pub fn start(
+        configured: Vec<(String, WorldOptions)>,
+        data_dir: Option<PathBuf>,
+        max_worlds: usize,
+    ) -> Result<Worlds> {
+        let worlds = Worlds {
+            worlds: RwLock::new(BTreeMap::new()),
+            data_dir,
+            max_worlds,
+            changes: Default::default(),
+        };
+        for (name, opts) in configured {
+            worlds.insert(&name, opts, None, false)?;
+        }
+        if let Some(dir) = &worlds.data_dir {
+            fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+            let mut found = Vec::new();
+            for entry in fs::read_dir(dir).context(format!("Failed to list {}", dir.display()))? {
+                let path = entry?.path();
+                if path.join(SETTINGS).is_file() {
+                    found.push(path);
+                }
+            }
+            found.sort();
+            for path in found {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let opts = WorldConfig::load(&path.join(SETTINGS))
+                    .and_then(|config| config.options())
+                    .context(format!("In world `{}'", name))?;
+                worlds.insert(&name, opts, None, true)?;
+            }
+        }
+        Ok(worlds)
+    }
+
+    fn insert(
+        &self,
+        name: &str,
+        opts: WorldOptions,
+        seed: Option<World>,
+        created: bool,
+    ) -> Result<Arc<Hosted>> {
+        check_name(name)?;
+        if self.worlds.read().unwrap().contains_key(name) {
+            bail!("There is already a world called `{}'", name);
+        }
+        let handle =
+            WorldHandle::start(name, opts, seed).context(format!("Failed to start `{}'", name))?;
+        info!("Started world `{}'", name);
+        let hosted = Arc::new(Hosted { handle, created });
+        self.worlds
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), hosted.clone());
+        Ok(hosted)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Hosted>> {
+        self.worlds.read().unwrap().get(name).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Hosted>> {
+        self.worlds.read().unwrap().values().cloned().collect()
+    }
+
+    /// Start a new world, empty or starting as `seed`, kept in the data directory if there
+    /// is one. Fails with `Refused` if the name is taken or there are too many worlds.
+    pub async fn create(
+        &self,
+        name: &str,
+        mut config: WorldConfig,
+        seed: Option<World>,
+    ) -> Result<Arc<Hosted>> {
+        let _changing = self.changes.lock().await;
+        check_name(name)?;
+        if self.get(name).is_some() {
+            return Err(Refused::Taken(name.to_owned()).into());
+        }
+        if self.worlds.read().unwrap().len() >= self.max_worlds {
+            return Err(Refused::Full(self.max_worlds).into());
+        }
+        let Some(data_dir) = &self.data_dir else {
+            let opts = config.options()?;
+            return self.insert(name, opts, seed, true);
+        };
+        let dir = data_dir.join(name);
+        if dir.exists() {
+            bail!("{} is in the way", dir.display());
+        }
+        let result = (|| {
+            fs::create_dir_all(&dir)?;
+            if let Some(seed) = &seed {
+                seed.save_snapshot(&dir.join(SEED))?;
+                config.world = Some(PathBuf::from(SEED));
+            }
+            config.journal = Some(PathBuf::from("journal"));
+            config.autosave = AutosaveConfig {
+                dir: Some(PathBuf::from("saves")),
+                ..config.autosave
+            };
+            let settings = dir.join(SETTINGS);
+            fs::write(&settings, toml::to_string(&config)?)?;
+            let opts = WorldConfig::load(&settings)?.options()?;
+            self.insert(name, opts, None, true)
+        })();
+        if result.is_err() {
+            _ = fs::remove_dir_all(&dir);
+        }
+        result
+    }
+
+    /// Stop a world created while running and delete everything kept for it
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        let _changing = self.changes.lock().await;
+        let Some(hosted) = self.get(name) else {
+            bail!("There is no world called `{}'", name);
+        };
+        if !hosted.created {
+            bail!("`{}' is configured, so it can't be deleted", name);
+        }
+        self.worlds.write().unwrap().remove(name);
+        hosted.handle.stop().await;
+        if let Some(data_dir) = &self.data_dir {
+            let dir = data_dir.join(name);
+            if dir.exists() {
+                fs::remove_dir_all(&dir).context(format!("Failed to remove {}", dir.display()))?;
+            }
+        }
+        info!("Deleted world `{}'", name);
+        Ok(())
+    }
+
+    /// Stop every world, saving them first
+    pub async fn stop(&self) {
+        for hosted in self.list() {
+            hosted.handle.stop().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    fn configured() -> Vec<(String, WorldOptions)> {
+        vec![(DEFAULT_WORLD.to_owned(), WorldOptions::default())]
+    }
+
+    fn refused(result: Result<Arc<Hosted>>) -> Refused {
+        match result {
+            Ok(_) => panic!("accepted"),
+            Err(e) => e.downcast().unwrap(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn created_worlds_are_kept() {
+        let dir = temp_dir(&[]);
+        let data_dir = dir.path().join("worlds");
+        let worlds = Worlds::start(configured(), Some(data_dir.clone()), 4).unwrap();
+        let seed = World::new();
+        let hosted = worlds
+            .create("demo", WorldConfig::default(), Some(seed))
+            .await
+            .unwrap();
+        assert!(hosted.created);
+        assert!(data_dir.join("demo").join(SETTINGS).is_file());
+        assert!(data_dir.join("demo").join(SEED).is_file());
+        worlds.stop().await;
+
+        // started again along with the server
+        let worlds = Worlds::start(configured(), Some(data_dir.clone()), 4).unwrap();
+        assert!(worlds.get("demo").unwrap().created);
+        assert!(!worlds.get(DEFAULT_WORLD).unwrap().created);
+        worlds.delete("demo").await.unwrap();
+        assert!(worlds.get("demo").is_none());
+        assert!(!data_dir.join("demo").exists());
+        assert!(worlds.delete(DEFAULT_WORLD).await.is_err());
+        worlds.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refusals() {
+        let worlds = Worlds::start(configured(), None, 2).unwrap();
+        assert_eq!(
+            refused(
+                worlds
+                    .create(DEFAULT_WORLD, WorldConfig::default(), None)
+                    .await
+            ),
+            Refused::Taken(DEFAULT_WORLD.to_owned())
+        );
+        worlds
+            .create("demo", WorldConfig::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            refused(worlds.create("more", WorldConfig::default(), None).await),
+            Refused::Full(2)
+        );
+        // names are checked before anything else
+        let err = worlds
+            .create("no/slash", WorldConfig::default(), None)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<Refused>().is_none());
+        // deleting makes room again
+        worlds.delete("demo").await.unwrap();
+        worlds
+            .create("more", WorldConfig::default(), None)
+            .await
+            .unwrap();
+        worlds.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn racing_over_a_name() {
+        let worlds = Arc::new(Worlds::start(configured(), None, 8).unwrap());
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let worlds = worlds.clone();
+                tokio::spawn(async move {
+                    let created = worlds.create("demo", WorldConfig::default(), None).await;
+                    created.err().map(|e| e.downcast::<Refused>().unwrap())
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+        results.sort_by_key(Option::is_some);
+        assert_eq!(results, [None, Some(Refused::Taken("demo".to_owned()))]);
+        assert_eq!(worlds.list().len(), 2);
+        worlds.stop().await;
+    }
+}